@@ -0,0 +1,351 @@
+//command line interface, every wizard answer can also be given as a flag
+//flags override the config file and CLUSTER_* variables they are applied on top of
+use crate::config::loadbalancer_config::{
+    Features, HashKey, LoadBalancerConfig, Node, Protocol, StickySession,
+};
+use crate::config::microserver_config::MicroServerConfig;
+use crate::config::server_config::{NodeRegistration, ServerConfig, ServerListener};
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "cluster",
+    version,
+    about = "Load balancer and node agent for a cluster"
+)]
+pub struct Cli {
+    //without a command the interactive menu is shown
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run or configure the load balancer
+    Lb {
+        #[command(subcommand)]
+        command: LbCommand,
+    },
+    /// Run or configure the node agent
+    Node {
+        #[command(subcommand)]
+        command: NodeCommand,
+    },
+    /// Run or configure a micro server hosting several services
+    Micro {
+        #[command(subcommand)]
+        command: MicroCommand,
+    },
+    /// Check a config and that its peers are reachable
+    Validate {
+        #[command(subcommand)]
+        target: ValidateTarget,
+    },
+    /// Work with config files
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Check a config file without starting anything
+    Check {
+        /// A .yaml, .yml or .toml config file
+        path: PathBuf,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum LbCommand {
+    /// Start balancing with the saved config and any overrides
+    Run(Box<LbArgs>),
+    /// Answer the setup wizard and save the config
+    Configure {
+        /// Config file to write the answers into instead of the saved config
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum NodeCommand {
+    /// Start the node agent with the saved config and any overrides
+    Run(NodeArgs),
+    /// Answer the setup wizard and save the config
+    Configure {
+        /// Config file to write the answers into instead of the saved config
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MicroCommand {
+    /// Start the services' proxies and agent with the saved config and any overrides
+    Run(MicroArgs),
+    /// Answer the setup wizard and save the config
+    Configure {
+        /// Config file to write the answers into instead of the saved config
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ValidateTarget {
+    /// Check the load balancer config and that its nodes are reachable
+    Lb(LbArgs),
+    /// Check the node config and that its load balancers are reachable
+    Node(NodeArgs),
+}
+
+#[derive(Debug, Default, Args)]
+pub struct LbArgs {
+    /// Config file (.yaml or .toml) to load instead of the saved config
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Address the load balancer listens on
+    #[arg(long)]
+    pub ip: Option<String>,
+    /// Port the load balancer listens on with --ip
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// Address with port to take traffic on, for example [::]:3000;
+    /// repeat for every address, replaces --ip and --port
+    #[arg(long)]
+    pub listen: Vec<String>,
+    /// Address with port serving /_lb/ apart from the traffic
+    #[arg(long)]
+    pub admin_listen: Option<String>,
+    /// Balancing protocol, for example least-connections
+    #[arg(long)]
+    pub protocol: Option<Protocol>,
+    /// Comma separated features, empty for none
+    #[arg(long, num_args = 0.., value_delimiter = ',')]
+    pub features: Option<Vec<Features>>,
+    /// Node url with optional settings, for example
+    /// http://10.0.0.1:8080,weight=2,agent_port=3001,max_connections=100;
+    /// repeat for every node, replaces the configured nodes
+    #[arg(long = "node")]
+    pub nodes: Vec<Node>,
+    /// Consistent hash key: client-ip, header:<name>, cookie:<name> or path-prefix:<segments>
+    #[arg(long)]
+    pub hash_key: Option<HashKey>,
+    /// Pin clients to a node with this affinity cookie
+    #[arg(long)]
+    pub sticky_cookie: Option<String>,
+    /// Lifetime of the affinity cookie
+    #[arg(long, requires = "sticky_cookie")]
+    pub sticky_ttl_secs: Option<u64>,
+    /// Key the affinity cookie is signed with, random per run when unset
+    #[arg(long, requires = "sticky_cookie")]
+    pub sticky_secret: Option<String>,
+}
+
+impl LbArgs {
+    pub fn apply(&self, cfg: &mut LoadBalancerConfig) {
+        if let Some(ip) = &self.ip {
+            cfg.ip = ip.clone();
+        }
+        if let Some(port) = self.port {
+            cfg.port = port;
+        }
+        if !self.listen.is_empty() {
+            cfg.listen = self.listen.clone();
+        }
+        if let Some(admin_listen) = &self.admin_listen {
+            cfg.admin_listen = Some(admin_listen.clone());
+        }
+        if let Some(protocol) = &self.protocol {
+            cfg.protocol = protocol.clone();
+        }
+        if let Some(features) = &self.features {
+            cfg.features = features.clone();
+        }
+        if !self.nodes.is_empty() {
+            cfg.nodes = self.nodes.clone();
+        }
+        if let Some(hash_key) = &self.hash_key {
+            cfg.hash_key = hash_key.clone();
+        }
+        if let Some(cookie_name) = &self.sticky_cookie {
+            let sticky = cfg.sticky_session.get_or_insert(StickySession {
+                cookie_name: cookie_name.clone(),
+                ttl_secs: 3600,
+                secret: None,
+            });
+            sticky.cookie_name = cookie_name.clone();
+            if let Some(ttl_secs) = self.sticky_ttl_secs {
+                sticky.ttl_secs = ttl_secs;
+            }
+            if let Some(secret) = &self.sticky_secret {
+                sticky.secret = Some(secret.clone());
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Args)]
+pub struct NodeArgs {
+    /// Config file (.yaml or .toml) to load instead of the saved config
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Address the node agent listens on
+    #[arg(long)]
+    pub ip: Option<String>,
+    /// Port the node agent listens on with --ip
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// Address with port to listen on, for example [::]:3001;
+    /// repeat for every address, replaces --ip and --port
+    #[arg(long)]
+    pub listen: Vec<String>,
+    /// Comma separated listeners, empty for none
+    #[arg(long, num_args = 0.., value_delimiter = ',')]
+    pub listeners: Option<Vec<ServerListener>>,
+    /// Load balancer this node registers with, as ip:port of its admin address,
+    /// or an ip that `cluster validate node` only pings; repeat for every one.
+    /// It does not limit who can reach the agent
+    #[arg(long = "loadbalancer")]
+    pub loadbalancers: Vec<String>,
+    /// Application url to register with the load balancers, for example
+    /// http://10.0.0.1:8080
+    #[arg(long)]
+    pub register: Option<String>,
+    /// Token sent to load balancers when registering
+    #[arg(long)]
+    pub token: Option<String>,
+}
+
+impl NodeArgs {
+    pub fn apply(&self, cfg: &mut ServerConfig) {
+        if let Some(ip) = &self.ip {
+            cfg.ip = ip.clone();
+        }
+        if let Some(port) = self.port {
+            cfg.port = port;
+        }
+        if !self.listen.is_empty() {
+            cfg.listen = self.listen.clone();
+        }
+        if let Some(listeners) = &self.listeners {
+            cfg.listener = listeners.clone();
+        }
+        if !self.loadbalancers.is_empty() {
+            cfg.loadbalancer_ip = self.loadbalancers.clone();
+        }
+        if let Some(url) = &self.register {
+            let registration = cfg.registration.get_or_insert(NodeRegistration {
+                url: url.clone(),
+                weight: 1,
+                max_connections: None,
+                token: None,
+            });
+            registration.url = url.clone();
+        }
+        if let (Some(token), Some(registration)) = (&self.token, cfg.registration.as_mut()) {
+            registration.token = Some(token.clone());
+        }
+    }
+}
+
+#[derive(Debug, Default, Args)]
+pub struct MicroArgs {
+    /// Config file (.yaml or .toml) to load instead of the saved config
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Address balancers reach the services and the agent on
+    #[arg(long)]
+    pub ip: Option<String>,
+    /// Port the embedded agent listens on
+    #[arg(long)]
+    pub agent_port: Option<u16>,
+    /// Token sent to balancers when registering
+    #[arg(long)]
+    pub token: Option<String>,
+}
+
+impl MicroArgs {
+    pub fn apply(&self, cfg: &mut MicroServerConfig) {
+        if let Some(ip) = &self.ip {
+            cfg.ip = ip.clone();
+        }
+        if let Some(agent_port) = self.agent_port {
+            cfg.agent_port = agent_port;
+        }
+        if let Some(token) = &self.token {
+            cfg.token = Some(token.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lb_args(args: &[&str]) -> LbArgs {
+        let cli = Cli::try_parse_from(["cluster", "lb", "run"].iter().chain(args)).unwrap();
+        match cli.command {
+            Some(Command::Lb {
+                command: LbCommand::Run(args),
+            }) => *args,
+            other => panic!("unexpected command {:?}", other),
+        }
+    }
+
+    #[test]
+    fn flags_override_the_config() {
+        let mut cfg = LoadBalancerConfig::default();
+        lb_args(&[
+            "--ip",
+            "10.0.0.5",
+            "--protocol",
+            "least-connections",
+            "--node",
+            "10.0.0.1:8080,weight=2",
+            "--node",
+            "10.0.0.2:8080",
+            "--features",
+            "health-check,api-health-check",
+        ])
+        .apply(&mut cfg);
+        assert_eq!(cfg.ip, "10.0.0.5");
+        assert!(matches!(cfg.protocol, Protocol::LeastConnections));
+        assert_eq!(cfg.nodes.len(), 2);
+        assert_eq!(cfg.nodes[0].weight, 2);
+        assert_eq!(
+            cfg.features,
+            vec![Features::HealthCheck, Features::ApiHealthCheck]
+        );
+    }
+
+    #[test]
+    fn missing_flags_keep_the_config() {
+        let mut cfg = LoadBalancerConfig {
+            ip: "10.0.0.5".to_string(),
+            features: vec![Features::HealthCheck],
+            nodes: vec!["10.0.0.1".parse().unwrap()],
+            ..LoadBalancerConfig::default()
+        };
+        lb_args(&[]).apply(&mut cfg);
+        assert_eq!(cfg.ip, "10.0.0.5");
+        assert_eq!(cfg.features, vec![Features::HealthCheck]);
+        assert_eq!(cfg.nodes.len(), 1);
+
+        lb_args(&["--features"]).apply(&mut cfg);
+        assert!(cfg.features.is_empty());
+    }
+
+    #[test]
+    fn sticky_flags_enable_affinity() {
+        let mut cfg = LoadBalancerConfig::default();
+        lb_args(&["--sticky-cookie", "route", "--sticky-ttl-secs", "60"]).apply(&mut cfg);
+        let sticky = cfg.sticky_session.unwrap();
+        assert_eq!(sticky.cookie_name, "route");
+        assert_eq!(sticky.ttl_secs, 60);
+        assert!(Cli::try_parse_from(["cluster", "lb", "run", "--sticky-ttl-secs", "60"]).is_err());
+    }
+}
@@ -0,0 +1,151 @@
+//probes of an application's api endpoints. the balancer runs them itself, or
+//asks every node agent for the report it keeps on its own endpoints at /apis
+use crate::common::outlier::now_millis;
+use crate::config::loadbalancer_config::{Api, ApiConfig};
+use log::{debug, error, info};
+use reqwest::{Client, Method};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::time::sleep;
+
+pub const PATH: &str = "/apis";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ApiStatus {
+    pub url: String,
+    pub method: String,
+    pub healthy: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>, //of the last attempt, none when nothing answered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub checked_ms: u64,
+}
+
+//every endpoint of a node, healthy when all of them are
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ApiReport {
+    pub healthy: bool,
+    pub apis: Vec<ApiStatus>,
+}
+
+impl ApiReport {
+    pub fn new(apis: Vec<ApiStatus>) -> Self {
+        ApiReport {
+            healthy: apis.iter().all(|api| api.healthy),
+            apis,
+        }
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &ApiStatus> {
+        self.apis.iter().filter(|api| !api.healthy)
+    }
+}
+
+//the status code when the api answered, validation keeps methods to GET and POST
+async fn attempt(client: &Client, api: &Api) -> Result<u16, reqwest::Error> {
+    let request = match api.method.as_str() {
+        "POST" => client.request(Method::POST, &api.url).json(&api.body),
+        _ => client.request(Method::GET, &api.url),
+    };
+    Ok(request.send().await?.status().as_u16())
+}
+
+//a failed api is tried again up to `failure_threshold` times before it is
+//reported as failed
+pub async fn probe(client: &Client, api: &Api, failure_threshold: u32) -> ApiStatus {
+    let mut status = None;
+    let mut error = None;
+    for i in 0..=failure_threshold {
+        match attempt(client, api).await {
+            Ok(code) => {
+                status = Some(code);
+                error = None;
+                if (200..300).contains(&code) {
+                    break;
+                }
+            }
+            Err(e) => {
+                debug!("{} failed on try {}: {}", api.url, i, e);
+                status = None;
+                error = Some(e.to_string());
+            }
+        }
+    }
+    ApiStatus {
+        url: api.url.clone(),
+        method: api.method.clone(),
+        healthy: status.is_some_and(|code| (200..300).contains(&code)),
+        status,
+        error,
+        checked_ms: now_millis(),
+    }
+}
+
+pub async fn probe_all(client: &Client, config: &ApiConfig) -> ApiReport {
+    let mut apis = vec![];
+    for api in config.apis.iter() {
+        apis.push(probe(client, api, config.failure_threshold).await);
+    }
+    ApiReport::new(apis)
+}
+
+pub fn client(config: &ApiConfig) -> Client {
+    Client::builder()
+        .timeout(Duration::from_millis(config.timeout_ms))
+        .build()
+        .expect("failed to build the client")
+}
+
+//keeps `report` up to date for the agent's /apis
+pub async fn check_apis(config: ApiConfig, report: Arc<RwLock<ApiReport>>) {
+    info!("api checks spawned for {} apis", config.apis.len());
+    let client = client(&config);
+    loop {
+        let next = probe_all(&client, &config).await;
+        for api in next.failed() {
+            error!("{}-> failed", api.url);
+        }
+        *report.write().unwrap() = next;
+        sleep(Duration::from_millis(config.check_interval_ms)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(url: &str, healthy: bool) -> ApiStatus {
+        ApiStatus {
+            url: url.to_string(),
+            method: "GET".to_string(),
+            healthy,
+            status: healthy.then_some(200),
+            error: None,
+            checked_ms: 0,
+        }
+    }
+
+    #[test]
+    fn one_failed_api_fails_the_report() {
+        let report = ApiReport::new(vec![status("/a", true), status("/b", false)]);
+        assert!(!report.healthy);
+        let failed: Vec<&str> = report.failed().map(|api| api.url.as_str()).collect();
+        assert_eq!(failed, vec!["/b"]);
+        assert!(ApiReport::new(vec![status("/a", true)]).healthy);
+    }
+
+    #[tokio::test]
+    async fn unreachable_apis_carry_the_error() {
+        let api = Api {
+            url: "http://127.0.0.1:9/health".to_string(),
+            method: "GET".to_string(),
+            body: None,
+        };
+        let status = probe(&Client::new(), &api, 1).await;
+        assert!(!status.healthy);
+        assert_eq!(status.status, None);
+        assert!(status.error.is_some());
+    }
+}
@@ -1,9 +1,12 @@
-use crate::subapps::loadbalancer::ApiConfig;
+use crate::common::api_checks::{self, ApiReport};
+use crate::common::network::NetworkRates;
+use crate::config::loadbalancer_config::{ApiConfig, HealthCheckConfig};
+use crate::subapps::loadbalancer::{Balancer, HealthTargets, NodeStats};
 use log::{error, info, warn};
 use netstat2::{get_sockets_info, AddressFamilyFlags, ProtocolFlags, ProtocolSocketInfo};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use std::{sync::Arc, time::Duration};
+use std::time::Duration;
 use tokio::time::sleep;
 
 #[derive(Deserialize, Serialize)]
@@ -15,18 +18,48 @@ pub struct ServerData {
 pub struct Payload {
     pub cpu: f64,
     pub ram: f64,
-    pub netspeed: Vec<f64>,
+    pub netspeed: Vec<f64>, //megabits per second received and sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<NetworkRates>, //missing from agents older than the interface counters
 }
 
-pub async fn health_check(servers: Arc<Vec<String>>) {
+//a probe passes when the agent answers with valid metrics within the timeout
+fn record_probe(ip: &str, node_stats: &NodeStats, passed: bool, cfg: &HealthCheckConfig) {
+    if let Some(state) = node_stats.health.record_probe(passed, cfg.rise, cfg.fall) {
+        if passed {
+            info!("server {} is {}, back in rotation", ip, state);
+        } else {
+            warn!("server {} is {}, taken out of rotation", ip, state);
+        }
+    }
+}
+
+//follows config reloads, the nodes and settings are read again every round
+pub async fn health_check(balancer: Balancer) {
     info!("health check spawned");
+    let client = Client::new();
     loop {
+        let HealthTargets {
+            servers,
+            stats,
+            cfg,
+            monitoring,
+            enabled,
+        } = balancer.health_targets();
+        if !enabled {
+            sleep(Duration::from_millis(cfg.interval_ms)).await;
+            continue;
+        }
         let mut server_data: Vec<Payload> = vec![];
-        for ip in servers.iter() {
-            let url = "http://".to_owned() + &ip + ":3001" + "/metrics";
-            let client = Client::new();
+        for (node, node_stats) in servers.iter().zip(stats.iter()) {
+            let ip = node.address();
+            let url = node.agent_url("/metrics");
+            let probe = client
+                .get(url)
+                .timeout(Duration::from_millis(cfg.timeout_ms))
+                .send();
 
-            match client.get(url).send().await {
+            match probe.await {
                 Ok(response) => {
                     if response.status().is_redirection() {
                         info!(
@@ -38,130 +71,118 @@ pub async fn health_check(servers: Arc<Vec<String>>) {
                     if response.status().is_server_error() {
                         warn!("server {} gave server error {}", ip, response.status());
                     }
-                    let metrics: Payload = response.json().await.expect("failed to parse JSON");
+                    let metrics: Payload = match response.json().await {
+                        Ok(metrics) => metrics,
+                        Err(e) => {
+                            warn!("server {} sent invalid metrics: {}", ip, e);
+                            node_stats.record_unreachable();
+                            record_probe(&ip, node_stats, false, &cfg);
+                            continue;
+                        }
+                    };
+                    //older or broken agents may send fewer rates, that is no valid answer
+                    let &[download, upload, ..] = metrics.netspeed.as_slice() else {
+                        warn!(
+                            "server {} sent invalid metrics: netspeed needs download and upload",
+                            ip
+                        );
+                        node_stats.record_unreachable();
+                        record_probe(&ip, node_stats, false, &cfg);
+                        continue;
+                    };
+                    node_stats.record_load(metrics.cpu, metrics.ram);
+                    record_probe(&ip, node_stats, true, &cfg);
 
-                    if metrics.cpu > 90.0 {
+                    if metrics.cpu > monitoring.cpu_percent {
                         warn!("cpu usage: {}", metrics.cpu);
                     }
-                    if metrics.ram > 90.0 {
+                    //ram is reported as a fraction of total memory
+                    if metrics.ram * 100.0 > monitoring.ram_percent {
                         warn!("ram usage: {}", metrics.ram);
                     }
-                    if metrics.netspeed[0] < 50.0 {
-                        warn!("download: {}", metrics.netspeed[0]);
+                    if download > monitoring.max_download_mbps {
+                        warn!("download: {}", download);
                     }
-                    if metrics.netspeed[1] < 50.0 {
-                        warn!("upload: {}", metrics.netspeed[1]);
+                    if upload > monitoring.max_upload_mbps {
+                        warn!("upload: {}", upload);
+                    }
+                    if let Some(network) = &metrics.network {
+                        let errors = network.rx_errors + network.tx_errors;
+                        let dropped = network.rx_dropped + network.tx_dropped;
+                        if errors > 0.0 || dropped > 0.0 {
+                            warn!(
+                                "server {} network errors: {}/s, dropped: {}/s",
+                                ip, errors, dropped
+                            );
+                        }
                     }
                     server_data.push(metrics);
                 }
                 Err(e) => {
                     error!("{}", e);
+                    node_stats.record_unreachable();
+                    record_probe(&ip, node_stats, false, &cfg);
                 }
             };
         }
-        let data = ServerData { server_data };
-        let url = "http://100.86.175.69:3000/data";
-        let client = Client::new();
-        match client.post(url).json(&data).send().await {
-            Ok(resp) => {
-                if !resp.status().is_success() {
-                    error!("{}", resp.status());
+        if let Some(url) = &monitoring.tui_url {
+            let data = ServerData { server_data };
+            match client.post(url).json(&data).send().await {
+                Ok(resp) => {
+                    if !resp.status().is_success() {
+                        error!("{}", resp.status());
+                    }
                 }
-            }
-            Err(err) => {
-                warn!("data not sent to tui {:?}", err);
-            }
-        }
-        sleep(Duration::from_secs(1)).await;
-    }
-}
-
-async fn failed_url_check(failure_threshold: u32, url: &String, client: Client) {
-    let mut pass = false;
-    for i in 0..failure_threshold {
-        let response = client.get(url).send().await;
-        match response {
-            Ok(resp) => {
-                if resp.status().is_success() {
-                    pass = true;
-                    break;
+                Err(err) => {
+                    warn!("data not sent to tui {:?}", err);
                 }
             }
-            Err(e) if e.is_timeout() => {
-                warn!("timeout for try {}", i)
-            }
-            Err(e) => {
-                warn!("{}", e);
-            }
         }
-    }
-    if pass == false {
-        error!("{}-> failed", url);
+        sleep(Duration::from_millis(cfg.interval_ms)).await;
     }
 }
 
-pub async fn api_health_check(api_config: ApiConfig) {
+//probes every api from here, or with from_agents reads the report each node
+//agent keeps on its own apis
+pub async fn api_health_check(api_config: ApiConfig, balancer: Balancer) {
     info!("api health check spawned");
-
-    let time_interval = api_config.check_interval_ms;
-    let timeout = api_config.timeout_ms;
-    let failure_threshold = api_config.failure_threshold;
-    let apis = api_config.apis;
+    let client = api_checks::client(&api_config);
     loop {
-        for api in apis.iter() {
-            let url = &api.url;
-            let action = &api.method;
-            let body = &api.body;
-            let client = Client::builder()
-                .timeout(Duration::from_secs(timeout))
-                .build()
-                .expect("failed to build the client");
-            match action.as_str() {
-                "GET" => {
-                    let response = client.get(url).send().await;
-                    match response {
-                        Ok(resp) => {
-                            if !resp.status().is_success() {
-                                failed_url_check(failure_threshold, url, client).await;
-                            }
-                        }
-                        Err(_) => {
-                            if let Ok(_) = client.get(url).send().await {
-                                failed_url_check(failure_threshold, url, client).await;
-                            } else {
-                                failed_url_check(failure_threshold, url, client).await;
-                            }
-                        }
+        if api_config.from_agents {
+            let HealthTargets { servers, .. } = balancer.health_targets();
+            for node in servers.iter() {
+                let ip = node.address();
+                let response = match client.get(node.agent_url(api_checks::PATH)).send().await {
+                    Ok(response) => response,
+                    Err(e) => {
+                        warn!("no api report from server {}: {}", ip, e);
+                        continue;
                     }
-                }
-                "POST" => {
-                    let response = client.post(url).json(&body).send().await;
-                    match response {
-                        Ok(resp) => {
-                            if !resp.status().is_success() {
-                                failed_url_check(failure_threshold, url, client).await;
-                            }
-                        }
-                        Err(_) => {
-                            if let Ok(_) = client.get(url).send().await {
-                                failed_url_check(failure_threshold, url, client).await;
-                            } else {
-                                failed_url_check(failure_threshold, url, client).await;
-                            }
+                };
+                match response.json::<ApiReport>().await {
+                    Ok(report) => {
+                        for api in report.failed() {
+                            error!("{} on server {}-> failed", api.url, ip);
                         }
                     }
+                    Err(e) => warn!("server {} sent an invalid api report: {}", ip, e),
                 }
-                _ => {}
+            }
+        } else {
+            let report = api_checks::probe_all(&client, &api_config).await;
+            for api in report.failed() {
+                error!("{}-> failed", api.url);
             }
         }
-        sleep(Duration::from_secs(time_interval)).await;
+        sleep(Duration::from_millis(api_config.check_interval_ms)).await;
     }
 }
 
-pub async fn load_balancer_connections() {
+pub async fn load_balancer_connections(balancer: Balancer) {
     info!("load_balancer_connections spawned");
     loop {
-        let af_flags = AddressFamilyFlags::IPV4;
+        let monitoring = balancer.monitoring();
+        let af_flags = AddressFamilyFlags::IPV4 | AddressFamilyFlags::IPV6;
         let proto_flags = ProtocolFlags::TCP;
 
         match get_sockets_info(af_flags, proto_flags) {
@@ -171,8 +192,7 @@ pub async fn load_balancer_connections() {
                     .filter(|info| matches!(info.protocol_socket_info, ProtocolSocketInfo::Tcp(_)))
                     .count();
                 info!("connections: {}", tcp_count);
-                if tcp_count > 100 {
-                    //env
+                if tcp_count > monitoring.max_connections {
                     warn!("connections: {}", tcp_count);
                 }
             }
@@ -180,6 +200,6 @@ pub async fn load_balancer_connections() {
                 warn!("Failed to get connections: {}", err);
             }
         }
-        sleep(Duration::from_secs(60)).await;
+        sleep(Duration::from_millis(monitoring.connections_interval_ms)).await;
     }
 }
@@ -0,0 +1,309 @@
+//per node circuit breaker around the upstream call
+//closed: requests flow and outcomes are counted over a rolling window
+//open: the failure rate got too high, requests to the node are refused until
+//      `open_ms` has passed
+//half-open: a few trial requests are let through, one success closes the
+//           breaker again and one failure reopens it
+use crate::config::loadbalancer_config::CircuitBreakerConfig;
+use serde::Serialize;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+const BUCKETS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let string = match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half-open",
+        };
+        f.write_str(string)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Rejection {
+    Open,
+    TooManyPending,
+}
+
+struct Inner {
+    state: BreakerState,
+    opened_at: u64,           //unix millis
+    half_open_in_flight: u32, //trial requests running while half-open
+    trial_started: u64,       //unix millis of the last trial request
+    //(bucket id, requests, failures), a bucket covers a tenth of the window
+    buckets: [(u64, u32, u32); BUCKETS as usize],
+}
+
+pub struct CircuitBreaker {
+    inner: Mutex<Inner>,
+    pending: Arc<AtomicUsize>, //requests waiting for response headers
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker {
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                opened_at: 0,
+                half_open_in_flight: 0,
+                trial_started: 0,
+                buckets: [(0, 0, 0); BUCKETS as usize],
+            }),
+            pending: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+//counts a request as pending until dropped
+pub struct PendingGuard {
+    pending: Arc<AtomicUsize>,
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl CircuitBreaker {
+    pub fn state(&self, now_ms: u64, cfg: &CircuitBreakerConfig) -> BreakerState {
+        let inner = self.inner.lock().unwrap();
+        Self::current_state(&inner, now_ms, cfg)
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    //an open breaker turns half-open once it has cooled down
+    fn current_state(inner: &Inner, now_ms: u64, cfg: &CircuitBreakerConfig) -> BreakerState {
+        match inner.state {
+            BreakerState::Open if now_ms >= inner.opened_at + cfg.open_ms => BreakerState::HalfOpen,
+            state => state,
+        }
+    }
+
+    //whether a new request would be let through, without reserving anything
+    pub fn allows(&self, now_ms: u64, cfg: &CircuitBreakerConfig) -> bool {
+        if !cfg.enabled {
+            return true;
+        }
+        let inner = self.inner.lock().unwrap();
+        match Self::current_state(&inner, now_ms, cfg) {
+            BreakerState::Closed => self.pending() < cfg.max_pending,
+            BreakerState::Open => false,
+            BreakerState::HalfOpen => {
+                inner.half_open_in_flight < cfg.half_open_requests
+                    || now_ms >= inner.trial_started + cfg.open_ms
+            }
+        }
+    }
+
+    //reserves a slot for one request, the returned guard tracks it as pending
+    pub fn acquire(
+        &self,
+        now_ms: u64,
+        cfg: &CircuitBreakerConfig,
+    ) -> Result<PendingGuard, Rejection> {
+        if cfg.enabled {
+            let mut inner = self.inner.lock().unwrap();
+            match Self::current_state(&inner, now_ms, cfg) {
+                BreakerState::Open => return Err(Rejection::Open),
+                BreakerState::HalfOpen => {
+                    //trials that never reported back (client went away) expire
+                    if now_ms >= inner.trial_started + cfg.open_ms {
+                        inner.half_open_in_flight = 0;
+                    }
+                    if inner.half_open_in_flight >= cfg.half_open_requests {
+                        return Err(Rejection::Open);
+                    }
+                    inner.state = BreakerState::HalfOpen;
+                    inner.half_open_in_flight += 1;
+                    inner.trial_started = now_ms;
+                }
+                BreakerState::Closed => {
+                    if self.pending() >= cfg.max_pending {
+                        return Err(Rejection::TooManyPending);
+                    }
+                }
+            }
+        }
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        Ok(PendingGuard {
+            pending: Arc::clone(&self.pending),
+        })
+    }
+
+    //returns the new state when this outcome moved the breaker
+    pub fn record(
+        &self,
+        now_ms: u64,
+        failed: bool,
+        cfg: &CircuitBreakerConfig,
+    ) -> Option<BreakerState> {
+        if !cfg.enabled {
+            return None;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let next = match inner.state {
+            BreakerState::HalfOpen => {
+                inner.half_open_in_flight = inner.half_open_in_flight.saturating_sub(1);
+                if failed {
+                    BreakerState::Open
+                } else {
+                    BreakerState::Closed
+                }
+            }
+            //late answers from before the breaker opened do not count
+            BreakerState::Open => return None,
+            BreakerState::Closed => {
+                let (requests, failures) = Self::count(&mut inner, now_ms, failed, cfg);
+                let tripped = requests >= cfg.min_requests
+                    && failures * 100 >= cfg.failure_rate_percent * requests;
+                if !tripped {
+                    return None;
+                }
+                BreakerState::Open
+            }
+        };
+        inner.state = next;
+        match next {
+            BreakerState::Open => inner.opened_at = now_ms,
+            _ => inner.buckets = [(0, 0, 0); BUCKETS as usize],
+        }
+        Some(next)
+    }
+
+    //hands back a half-open trial slot for a request that proved nothing about
+    //the node, like one the client made too large, without counting an outcome
+    pub fn release(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state == BreakerState::HalfOpen {
+            inner.half_open_in_flight = inner.half_open_in_flight.saturating_sub(1);
+        }
+    }
+
+    //adds an outcome to the rolling window and returns its totals
+    fn count(
+        inner: &mut Inner,
+        now_ms: u64,
+        failed: bool,
+        cfg: &CircuitBreakerConfig,
+    ) -> (u32, u32) {
+        let width = (cfg.window_ms / BUCKETS).max(1);
+        let id = now_ms / width;
+        let bucket = &mut inner.buckets[(id % BUCKETS) as usize];
+        if bucket.0 != id {
+            *bucket = (id, 0, 0);
+        }
+        bucket.1 += 1;
+        bucket.2 += failed as u32;
+
+        inner
+            .buckets
+            .iter()
+            .filter(|(bucket_id, _, _)| bucket_id + BUCKETS > id)
+            .fold((0, 0), |(requests, failures), (_, r, f)| {
+                (requests + r, failures + f)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg() -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            min_requests: 4,
+            failure_rate_percent: 50,
+            window_ms: 1000,
+            open_ms: 500,
+            max_pending: 2,
+            half_open_requests: 1,
+            ..CircuitBreakerConfig::default()
+        }
+    }
+
+    fn trip(breaker: &CircuitBreaker, now: u64) {
+        for failed in [false, false, true] {
+            assert_eq!(breaker.record(now, failed, &cfg()), None);
+        }
+        assert_eq!(breaker.record(now, true, &cfg()), Some(BreakerState::Open));
+    }
+
+    #[test]
+    fn opens_once_failure_rate_is_reached() {
+        let breaker = CircuitBreaker::default();
+        trip(&breaker, 0);
+        assert!(!breaker.allows(100, &cfg()));
+        assert_eq!(breaker.acquire(100, &cfg()).err(), Some(Rejection::Open));
+    }
+
+    #[test]
+    fn old_outcomes_fall_out_of_the_window() {
+        let breaker = CircuitBreaker::default();
+        for _ in 0..3 {
+            breaker.record(0, true, &cfg());
+        }
+        assert_eq!(breaker.record(2000, true, &cfg()), None);
+        assert_eq!(breaker.state(2000, &cfg()), BreakerState::Closed);
+    }
+
+    #[test]
+    fn half_open_success_closes() {
+        let breaker = CircuitBreaker::default();
+        trip(&breaker, 0);
+        assert_eq!(breaker.state(600, &cfg()), BreakerState::HalfOpen);
+        let _trial = breaker.acquire(600, &cfg()).unwrap();
+        assert!(!breaker.allows(600, &cfg()));
+        assert_eq!(
+            breaker.record(650, false, &cfg()),
+            Some(BreakerState::Closed)
+        );
+    }
+
+    #[test]
+    fn half_open_failure_reopens() {
+        let breaker = CircuitBreaker::default();
+        trip(&breaker, 0);
+        let _trial = breaker.acquire(600, &cfg()).unwrap();
+        assert_eq!(breaker.record(650, true, &cfg()), Some(BreakerState::Open));
+        assert!(!breaker.allows(700, &cfg()));
+    }
+
+    #[test]
+    fn released_trial_leaves_the_breaker_half_open() {
+        let breaker = CircuitBreaker::default();
+        trip(&breaker, 0);
+        let trial = breaker.acquire(600, &cfg()).unwrap();
+        drop(trial);
+        breaker.release();
+        assert_eq!(breaker.state(650, &cfg()), BreakerState::HalfOpen);
+        assert!(breaker.acquire(650, &cfg()).is_ok());
+    }
+
+    #[test]
+    fn pending_requests_are_capped() {
+        let breaker = CircuitBreaker::default();
+        let first = breaker.acquire(0, &cfg()).unwrap();
+        let _second = breaker.acquire(0, &cfg()).unwrap();
+        assert_eq!(
+            breaker.acquire(0, &cfg()).err(),
+            Some(Rejection::TooManyPending)
+        );
+        drop(first);
+        assert!(breaker.acquire(0, &cfg()).is_ok());
+    }
+}
@@ -0,0 +1,177 @@
+//header rewriting for proxied requests and responses
+//hop-by-hop headers only describe one connection (rfc 9110 section 7.6.1) so they
+//are dropped in both directions, everything else is passed through untouched
+use axum::http::{
+    header::{CONNECTION, FORWARDED, HOST, TE, TRAILER, TRANSFER_ENCODING, UPGRADE},
+    HeaderMap, HeaderName, HeaderValue,
+};
+use std::net::{IpAddr, SocketAddr};
+
+const HOP_BY_HOP: [&str; 4] = [
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+];
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+
+//headers to send upstream, with the client recorded in the x-forwarded-* and
+//forwarded headers. incoming forwarding headers are only extended when the
+//balancer sits behind a trusted proxy, otherwise clients could spoof them
+pub fn request_headers(incoming: &HeaderMap, client: SocketAddr, trust: bool) -> HeaderMap {
+    let mut headers = incoming.clone();
+    strip_hop_by_hop(&mut headers);
+    //the upstream host comes from the node url
+    headers.remove(HOST);
+
+    let client_ip = client.ip().to_string();
+    let host = incoming
+        .get(HOST)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let proto = match incoming.get(X_FORWARDED_PROTO) {
+        Some(proto) if trust => proto.to_str().unwrap_or("http").to_string(),
+        _ => "http".to_string(),
+    };
+
+    let forwarded_for = match incoming.get(X_FORWARDED_FOR) {
+        Some(existing) if trust => match existing.to_str() {
+            Ok(existing) => format!("{}, {}", existing, client_ip),
+            Err(_) => client_ip.clone(),
+        },
+        _ => client_ip.clone(),
+    };
+    set(&mut headers, X_FORWARDED_FOR, &forwarded_for);
+    set(&mut headers, X_FORWARDED_PROTO, &proto);
+    match (&host, incoming.get(X_FORWARDED_HOST)) {
+        (_, Some(existing)) if trust => {
+            headers.insert(X_FORWARDED_HOST, existing.clone());
+        }
+        (Some(host), _) => set(&mut headers, X_FORWARDED_HOST, host),
+        _ => {
+            headers.remove(X_FORWARDED_HOST);
+        }
+    }
+
+    let mut element = format!("for={};proto={}", forwarded_node(client.ip()), proto);
+    if let Some(host) = &host {
+        element += &format!(";host=\"{}\"", host);
+    }
+    let forwarded = match incoming.get(FORWARDED) {
+        Some(existing) if trust => match existing.to_str() {
+            Ok(existing) => format!("{}, {}", existing, element),
+            Err(_) => element,
+        },
+        _ => element,
+    };
+    set(&mut headers, FORWARDED.as_str(), &forwarded);
+    headers
+}
+
+//headers to send back to the client
+pub fn response_headers(upstream: &HeaderMap) -> HeaderMap {
+    let mut headers = upstream.clone();
+    strip_hop_by_hop(&mut headers);
+    headers
+}
+
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    //headers named in `connection` are hop-by-hop as well
+    let listed: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::try_from(name.trim()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in [CONNECTION, TE, TRAILER, TRANSFER_ENCODING, UPGRADE] {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+}
+
+//ipv6 addresses are quoted and bracketed in `forwarded` (rfc 7239)
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    }
+}
+
+fn set(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    match HeaderValue::from_str(value) {
+        Ok(value) => {
+            headers.insert(name, value);
+        }
+        Err(_) => {
+            headers.remove(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client() -> SocketAddr {
+        "10.0.0.9:5000".parse().unwrap()
+    }
+
+    fn incoming() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("host", HeaderValue::from_static("example.com"));
+        headers.insert("authorization", HeaderValue::from_static("Bearer abc"));
+        headers.insert("connection", HeaderValue::from_static("keep-alive, x-hop"));
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        headers.insert("x-hop", HeaderValue::from_static("1"));
+        headers.insert("x-forwarded-for", HeaderValue::from_static("1.2.3.4"));
+        headers
+    }
+
+    #[test]
+    fn end_to_end_headers_are_kept() {
+        let headers = request_headers(&incoming(), client(), false);
+        assert_eq!(headers["authorization"], "Bearer abc");
+    }
+
+    #[test]
+    fn hop_by_hop_headers_are_dropped() {
+        let headers = request_headers(&incoming(), client(), false);
+        for name in ["connection", "keep-alive", "x-hop", "host"] {
+            assert!(!headers.contains_key(name), "{} was forwarded", name);
+        }
+    }
+
+    #[test]
+    fn untrusted_forwarding_headers_are_replaced() {
+        let headers = request_headers(&incoming(), client(), false);
+        assert_eq!(headers["x-forwarded-for"], "10.0.0.9");
+        assert_eq!(headers["x-forwarded-proto"], "http");
+        assert_eq!(headers["x-forwarded-host"], "example.com");
+        assert_eq!(
+            headers["forwarded"],
+            "for=10.0.0.9;proto=http;host=\"example.com\""
+        );
+    }
+
+    #[test]
+    fn trusted_forwarding_headers_are_extended() {
+        let headers = request_headers(&incoming(), client(), true);
+        assert_eq!(headers["x-forwarded-for"], "1.2.3.4, 10.0.0.9");
+    }
+
+    #[test]
+    fn ipv6_clients_are_quoted_in_forwarded() {
+        let client: SocketAddr = "[::1]:5000".parse().unwrap();
+        let headers = request_headers(&HeaderMap::new(), client, false);
+        assert_eq!(headers["forwarded"], "for=\"[::1]\";proto=http");
+    }
+}
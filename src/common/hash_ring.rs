@@ -0,0 +1,173 @@
+//ketama style consistent hash ring
+//every node is placed on the ring many times (scaled by its weight) so keys
+//spread evenly, and adding or removing a node only moves the keys that land
+//on that node's points
+use crate::config::loadbalancer_config::Node;
+
+//points per unit of weight
+const POINTS_PER_WEIGHT: u32 = 160;
+
+pub struct HashRing {
+    points: Vec<(u64, usize)>, //(hash, node index), sorted by hash
+}
+
+impl HashRing {
+    pub fn new(nodes: &[Node]) -> Self {
+        let mut points = vec![];
+        for (index, node) in nodes.iter().enumerate() {
+            //points depend only on the node's address, not its position in the list
+            for point in 0..node.weight * POINTS_PER_WEIGHT {
+                let hash = hash(format!("{}-{}", node.address(), point).as_bytes());
+                points.push((hash, index));
+            }
+        }
+        points.sort_unstable();
+        HashRing { points }
+    }
+
+    //first node clockwise from the key's hash that passes `is_available`
+    pub fn get_available(&self, key: &[u8], is_available: impl Fn(usize) -> bool) -> Option<usize> {
+        if self.points.is_empty() {
+            return None;
+        }
+        let hash = hash(key);
+        let start = self.points.partition_point(|&(point, _)| point < hash);
+        let len = self.points.len();
+        (0..len)
+            .map(|offset| self.points[(start + offset) % len].1)
+            .find(|&index| is_available(index))
+    }
+}
+
+//fnv-1a followed by the murmur3 finalizer to spread nearby inputs across the ring
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^= hash >> 33;
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::loadbalancer_config::parse_node_url;
+
+    const KEYS: usize = 10_000;
+
+    impl HashRing {
+        fn get(&self, key: &[u8]) -> Option<usize> {
+            self.get_available(key, |_| true)
+        }
+    }
+
+    fn nodes(count: usize) -> Vec<Node> {
+        (0..count)
+            .map(|i| Node {
+                url: parse_node_url(&format!("10.0.0.{}:8080", i + 1)).unwrap(),
+                agent_port: 3001,
+                weight: 1,
+                max_connections: None,
+                draining: false,
+            })
+            .collect()
+    }
+
+    //maps every key to the ip of the node that owns it
+    fn owners(nodes: &[Node]) -> Vec<String> {
+        let ring = HashRing::new(nodes);
+        (0..KEYS)
+            .map(|key| {
+                let index = ring.get(format!("user-{}", key).as_bytes()).unwrap();
+                nodes[index].address()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn empty_ring_has_no_owner() {
+        assert_eq!(HashRing::new(&[]).get(b"user"), None);
+    }
+
+    #[test]
+    fn same_key_maps_to_same_node() {
+        let ring = HashRing::new(&nodes(5));
+        assert_eq!(ring.get(b"user-42"), ring.get(b"user-42"));
+    }
+
+    #[test]
+    fn keys_are_spread_evenly() {
+        let nodes = nodes(5);
+        let owners = owners(&nodes);
+        for node in nodes.iter() {
+            let share =
+                owners.iter().filter(|ip| **ip == node.address()).count() as f64 / KEYS as f64;
+            assert!(
+                share > 0.1 && share < 0.3,
+                "{} owns {}",
+                node.address(),
+                share
+            );
+        }
+    }
+
+    #[test]
+    fn weight_scales_share() {
+        let mut nodes = nodes(2);
+        nodes[1].weight = 3;
+        let owners = owners(&nodes);
+        let heavy = owners
+            .iter()
+            .filter(|ip| **ip == nodes[1].address())
+            .count() as f64
+            / KEYS as f64;
+        assert!(heavy > 0.65 && heavy < 0.85, "heavy node owns {}", heavy);
+    }
+
+    #[test]
+    fn adding_a_node_only_moves_keys_to_it() {
+        let before = owners(&nodes(5));
+        let grown = nodes(6);
+        let after = owners(&grown);
+
+        let moved: Vec<usize> = (0..KEYS).filter(|&i| before[i] != after[i]).collect();
+        for &i in moved.iter() {
+            assert_eq!(after[i], grown[5].address());
+        }
+        let share = moved.len() as f64 / KEYS as f64;
+        assert!(share < 0.25, "{} of keys moved", share);
+    }
+
+    #[test]
+    fn removing_a_node_only_moves_its_keys() {
+        let full = nodes(5);
+        let before = owners(&full);
+        let shrunk: Vec<Node> = full
+            .iter()
+            .filter(|n| n.address() != full[2].address())
+            .cloned()
+            .collect();
+        let after = owners(&shrunk);
+
+        for i in 0..KEYS {
+            if before[i] != full[2].address() {
+                assert_eq!(before[i], after[i]);
+            }
+        }
+    }
+
+    #[test]
+    fn unavailable_nodes_are_skipped() {
+        let ring = HashRing::new(&nodes(3));
+        let owner = ring.get(b"user-7").unwrap();
+        let fallback = ring.get_available(b"user-7", |i| i != owner).unwrap();
+        assert_ne!(owner, fallback);
+        assert_eq!(ring.get_available(b"user-7", |_| false), None);
+    }
+}
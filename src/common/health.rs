@@ -0,0 +1,134 @@
+//active health state of a node, driven by the probes in `health_check`
+//a node needs `fall` failed probes in a row to leave rotation and `rise`
+//passed probes in a row to come back, so a single blip does not flap it
+use std::fmt;
+use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Health {
+    Healthy,
+    Unhealthy,
+    Draining, //takes no new requests, set from config
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let string = match self {
+            Health::Healthy => "healthy",
+            Health::Unhealthy => "unhealthy",
+            Health::Draining => "draining",
+        };
+        f.write_str(string)
+    }
+}
+
+impl Health {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Health::Healthy,
+            1 => Health::Unhealthy,
+            _ => Health::Draining,
+        }
+    }
+}
+
+//nodes start healthy so traffic flows before the first probe comes back
+#[derive(Default)]
+pub struct NodeHealth {
+    state: AtomicU8,
+    successes: AtomicU32, //passed probes in a row
+    failures: AtomicU32,  //failed probes in a row
+}
+
+impl NodeHealth {
+    pub fn state(&self) -> Health {
+        Health::from_u8(self.state.load(Ordering::SeqCst))
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.state() == Health::Healthy
+    }
+
+    pub fn drain(&self) {
+        self.state.store(Health::Draining as u8, Ordering::SeqCst);
+    }
+
+    //puts a drained node back in rotation, probes take it out again if it is down
+    pub fn resume(&self) {
+        if self.state() == Health::Draining {
+            self.successes.store(0, Ordering::SeqCst);
+            self.failures.store(0, Ordering::SeqCst);
+            self.state.store(Health::Healthy as u8, Ordering::SeqCst);
+        }
+    }
+
+    //returns the new state when this probe moved the node in or out of rotation
+    pub fn record_probe(&self, passed: bool, rise: u32, fall: u32) -> Option<Health> {
+        let (streak, other) = if passed {
+            (&self.successes, &self.failures)
+        } else {
+            (&self.failures, &self.successes)
+        };
+        other.store(0, Ordering::SeqCst);
+        let streak = streak.fetch_add(1, Ordering::SeqCst) + 1;
+
+        let next = match self.state() {
+            Health::Draining => return None,
+            Health::Unhealthy if passed && streak >= rise => Health::Healthy,
+            Health::Healthy if !passed && streak >= fall => Health::Unhealthy,
+            _ => return None,
+        };
+        self.state.store(next as u8, Ordering::SeqCst);
+        Some(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_falls_after_consecutive_failures() {
+        let health = NodeHealth::default();
+        assert_eq!(health.record_probe(false, 2, 3), None);
+        assert_eq!(health.record_probe(false, 2, 3), None);
+        assert_eq!(health.record_probe(false, 2, 3), Some(Health::Unhealthy));
+        assert!(!health.is_healthy());
+    }
+
+    #[test]
+    fn a_pass_resets_the_failure_streak() {
+        let health = NodeHealth::default();
+        health.record_probe(false, 2, 2);
+        health.record_probe(true, 2, 2);
+        assert_eq!(health.record_probe(false, 2, 2), None);
+        assert!(health.is_healthy());
+    }
+
+    #[test]
+    fn node_rises_after_consecutive_passes() {
+        let health = NodeHealth::default();
+        health.record_probe(false, 2, 1);
+        assert_eq!(health.record_probe(true, 2, 1), None);
+        assert_eq!(health.record_probe(true, 2, 1), Some(Health::Healthy));
+    }
+
+    #[test]
+    fn draining_node_ignores_probes() {
+        let health = NodeHealth::default();
+        health.drain();
+        assert_eq!(health.record_probe(true, 1, 1), None);
+        assert_eq!(health.state(), Health::Draining);
+    }
+
+    #[test]
+    fn resumed_node_is_back_in_rotation() {
+        let health = NodeHealth::default();
+        health.drain();
+        health.resume();
+        assert!(health.is_healthy());
+        assert_eq!(health.record_probe(false, 1, 1), Some(Health::Unhealthy));
+        health.resume();
+        assert_eq!(health.state(), Health::Unhealthy);
+    }
+}
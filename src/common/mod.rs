@@ -1,2 +1,15 @@
+pub mod api_checks;
 pub mod background;
+pub mod circuit_breaker;
+pub mod forwarding;
+pub mod hash_ring;
+pub mod health;
+pub mod network;
+pub mod outlier;
+pub mod registration;
+pub mod reload;
+pub mod retry;
+pub mod sampler;
+pub mod serve;
+pub mod sticky;
 pub mod utilities;
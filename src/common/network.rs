@@ -0,0 +1,216 @@
+//network throughput from the host's own interface counters, read by the
+//metrics sampler so /metrics never waits on the network and needs no internet
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+//running totals over every interface but loopback
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Counters {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+    pub rx_errors: u64,
+    pub tx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_dropped: u64,
+}
+
+impl Counters {
+    fn add(&mut self, other: &Counters) {
+        self.rx_bytes += other.rx_bytes;
+        self.tx_bytes += other.tx_bytes;
+        self.rx_packets += other.rx_packets;
+        self.tx_packets += other.tx_packets;
+        self.rx_errors += other.rx_errors;
+        self.tx_errors += other.tx_errors;
+        self.rx_dropped += other.rx_dropped;
+        self.tx_dropped += other.tx_dropped;
+    }
+}
+
+//per second over the last sample interval
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct NetworkRates {
+    pub rx_bytes: f64,
+    pub tx_bytes: f64,
+    pub rx_packets: f64,
+    pub tx_packets: f64,
+    pub rx_errors: f64,
+    pub tx_errors: f64,
+    pub rx_dropped: f64,
+    pub tx_dropped: f64,
+}
+
+impl NetworkRates {
+    //counters that went backwards were reset, like an interface that came back
+    pub fn between(before: &Counters, after: &Counters, elapsed: Duration) -> Self {
+        let secs = elapsed.as_secs_f64();
+        if secs <= 0.0 {
+            return NetworkRates::default();
+        }
+        let rate = |before: u64, after: u64| after.saturating_sub(before) as f64 / secs;
+        NetworkRates {
+            rx_bytes: rate(before.rx_bytes, after.rx_bytes),
+            tx_bytes: rate(before.tx_bytes, after.tx_bytes),
+            rx_packets: rate(before.rx_packets, after.rx_packets),
+            tx_packets: rate(before.tx_packets, after.tx_packets),
+            rx_errors: rate(before.rx_errors, after.rx_errors),
+            tx_errors: rate(before.tx_errors, after.tx_errors),
+            rx_dropped: rate(before.rx_dropped, after.rx_dropped),
+            tx_dropped: rate(before.tx_dropped, after.tx_dropped),
+        }
+    }
+
+    //megabits per second received and sent, what `Metrics::netspeed` carries
+    pub fn mbps(&self) -> [f64; 2] {
+        [
+            self.rx_bytes * 8.0 / 1_000_000.0,
+            self.tx_bytes * 8.0 / 1_000_000.0,
+        ]
+    }
+}
+
+fn is_loopback(name: &str) -> bool {
+    name == "lo" || name.starts_with("lo0")
+}
+
+//the contents of /proc/net/dev: two header lines, then one interface per line
+//with eight receive columns followed by eight transmit columns
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_proc_net_dev(source: &str) -> Counters {
+    let mut total = Counters::default();
+    for line in source.lines().skip(2) {
+        let Some((name, columns)) = line.split_once(':') else {
+            continue;
+        };
+        if is_loopback(name.trim()) {
+            continue;
+        }
+        let columns: Vec<u64> = columns
+            .split_whitespace()
+            .map(|column| column.parse().unwrap_or(0))
+            .collect();
+        if columns.len() < 16 {
+            continue;
+        }
+        total.add(&Counters {
+            rx_bytes: columns[0],
+            rx_packets: columns[1],
+            rx_errors: columns[2],
+            rx_dropped: columns[3],
+            tx_bytes: columns[8],
+            tx_packets: columns[9],
+            tx_errors: columns[10],
+            tx_dropped: columns[11],
+        });
+    }
+    total
+}
+
+#[cfg(target_os = "linux")]
+pub struct Reader;
+
+#[cfg(target_os = "linux")]
+impl Reader {
+    pub fn new() -> Self {
+        Reader
+    }
+
+    pub fn read(&mut self) -> Option<Counters> {
+        match std::fs::read_to_string("/proc/net/dev") {
+            Ok(source) => Some(parse_proc_net_dev(&source)),
+            Err(e) => {
+                log::warn!("failed to read /proc/net/dev: {}", e);
+                None
+            }
+        }
+    }
+}
+
+//sysinfo has no drop counters, they stay 0 here
+#[cfg(not(target_os = "linux"))]
+pub struct Reader {
+    networks: sysinfo::Networks,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Reader {
+    pub fn new() -> Self {
+        Reader {
+            networks: sysinfo::Networks::new_with_refreshed_list(),
+        }
+    }
+
+    pub fn read(&mut self) -> Option<Counters> {
+        self.networks.refresh(true);
+        let mut total = Counters::default();
+        for (name, data) in self.networks.iter() {
+            if is_loopback(name) {
+                continue;
+            }
+            total.add(&Counters {
+                rx_bytes: data.total_received(),
+                tx_bytes: data.total_transmitted(),
+                rx_packets: data.total_packets_received(),
+                tx_packets: data.total_packets_transmitted(),
+                rx_errors: data.total_errors_on_received(),
+                tx_errors: data.total_errors_on_transmitted(),
+                rx_dropped: 0,
+                tx_dropped: 0,
+            });
+        }
+        Some(total)
+    }
+}
+
+impl Default for Reader {
+    fn default() -> Self {
+        Reader::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROC_NET_DEV: &str = "\
+Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo: 95621107   14937    0    0    0     0          0         0 95621107   14937    0    0    0     0       0          0
+  eth0: 1000    10    1    2    0     0          0         0     2000      20    3    4    0     0       0          0
+  eth1: 500      5    0    0    0     0          0         0      500       5    0    0    0     0       0          0
+";
+
+    #[test]
+    fn loopback_is_left_out() {
+        let counters = parse_proc_net_dev(PROC_NET_DEV);
+        assert_eq!(counters.rx_bytes, 1500);
+        assert_eq!(counters.tx_bytes, 2500);
+        assert_eq!(counters.rx_packets, 15);
+        assert_eq!(counters.rx_errors, 1);
+        assert_eq!(counters.rx_dropped, 2);
+        assert_eq!(counters.tx_errors, 3);
+        assert_eq!(counters.tx_dropped, 4);
+    }
+
+    #[test]
+    fn rates_are_per_second() {
+        let before = Counters {
+            rx_bytes: 1_000_000,
+            tx_bytes: 500,
+            ..Counters::default()
+        };
+        let after = Counters {
+            rx_bytes: 3_500_000,
+            tx_bytes: 0, //reset
+            rx_packets: 10,
+            ..Counters::default()
+        };
+        let rates = NetworkRates::between(&before, &after, Duration::from_secs(2));
+        assert_eq!(rates.rx_bytes, 1_250_000.0);
+        assert_eq!(rates.tx_bytes, 0.0);
+        assert_eq!(rates.rx_packets, 5.0);
+        assert_eq!(rates.mbps(), [10.0, 0.0]);
+    }
+}
@@ -0,0 +1,112 @@
+//passive outlier detection, modelled on envoy's consecutive 5xx detector
+//nodes that fail too many proxied requests in a row are ejected from rotation
+//for a while, and each repeat ejection doubles the time they stay out
+use crate::config::loadbalancer_config::OutlierDetectionConfig;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Default)]
+pub struct NodeOutlier {
+    failures: AtomicU32,      //failed requests in a row
+    ejections: AtomicU32,     //ejections since the node last stayed clean
+    ejected_at: AtomicU64,    //unix millis of the last ejection
+    ejected_until: AtomicU64, //unix millis, 0 when never ejected
+}
+
+impl NodeOutlier {
+    pub fn is_ejected(&self, now_ms: u64) -> bool {
+        now_ms < self.ejected_until.load(Ordering::SeqCst)
+    }
+
+    pub fn record_success(&self) {
+        self.failures.store(0, Ordering::SeqCst);
+    }
+
+    //true when this failure reached the threshold
+    pub fn record_failure(&self, threshold: u32) -> bool {
+        let failures = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
+        failures >= threshold
+    }
+
+    //takes the node out and returns for how long
+    pub fn eject(&self, now_ms: u64, cfg: &OutlierDetectionConfig) -> Duration {
+        //a node that stayed in for longer than the max ejection starts over
+        let last = self.ejected_at.load(Ordering::SeqCst);
+        if now_ms.saturating_sub(last) > cfg.max_ejection_ms {
+            self.ejections.store(0, Ordering::SeqCst);
+        }
+        let ejections = self.ejections.fetch_add(1, Ordering::SeqCst);
+        let millis = cfg
+            .base_ejection_ms
+            .saturating_mul(1 << ejections.min(20))
+            .min(cfg.max_ejection_ms);
+
+        self.failures.store(0, Ordering::SeqCst);
+        self.ejected_at.store(now_ms, Ordering::SeqCst);
+        self.ejected_until.store(now_ms + millis, Ordering::SeqCst);
+        Duration::from_millis(millis)
+    }
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg() -> OutlierDetectionConfig {
+        OutlierDetectionConfig {
+            consecutive_failures: 3,
+            base_ejection_ms: 1000,
+            max_ejection_ms: 5000,
+            ..OutlierDetectionConfig::default()
+        }
+    }
+
+    #[test]
+    fn threshold_needs_consecutive_failures() {
+        let outlier = NodeOutlier::default();
+        assert!(!outlier.record_failure(3));
+        assert!(!outlier.record_failure(3));
+        outlier.record_success();
+        assert!(!outlier.record_failure(3));
+        assert!(!outlier.record_failure(3));
+        assert!(outlier.record_failure(3));
+    }
+
+    #[test]
+    fn ejection_expires() {
+        let outlier = NodeOutlier::default();
+        let duration = outlier.eject(10_000, &cfg());
+        assert_eq!(duration, Duration::from_millis(1000));
+        assert!(outlier.is_ejected(10_500));
+        assert!(!outlier.is_ejected(11_000));
+    }
+
+    #[test]
+    fn repeat_ejections_back_off_up_to_the_max() {
+        let outlier = NodeOutlier::default();
+        let mut now = 10_000;
+        let mut durations = vec![];
+        for _ in 0..4 {
+            let duration = outlier.eject(now, &cfg());
+            now += duration.as_millis() as u64;
+            durations.push(duration.as_millis());
+        }
+        assert_eq!(durations, vec![1000, 2000, 4000, 5000]);
+    }
+
+    #[test]
+    fn backoff_resets_after_a_clean_period() {
+        let outlier = NodeOutlier::default();
+        outlier.eject(10_000, &cfg());
+        outlier.eject(11_000, &cfg());
+        let duration = outlier.eject(30_000, &cfg());
+        assert_eq!(duration, Duration::from_millis(1000));
+    }
+}
@@ -0,0 +1,236 @@
+//agents announcing themselves to a balancer, on top of its static node list
+//a registration is posted to /_lb/nodes on the balancer's admin address and
+//replaces any earlier one for the same node address. the balancer answers with
+//a lease, posting again before it runs out is the heartbeat, and a node that
+//lets it run out is taken out of rotation
+use crate::config::loadbalancer_config::Node;
+use axum::http::{header::AUTHORIZATION, HeaderMap};
+use hmac::{Hmac, Mac};
+use log::{debug, info, warn};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::sleep;
+
+pub const PATH: &str = "/_lb/nodes";
+
+//how long to wait before trying a balancer that could not be reached again
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Registration {
+    pub name: String, //service or host name, shown in the balancer's logs
+    pub node: Node,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct Lease {
+    pub ttl_ms: u64, //the registration is dropped unless renewed within this
+}
+
+impl Lease {
+    //a third of the lease, so two heartbeats in a row can get lost
+    pub fn renew_after(&self) -> Duration {
+        Duration::from_millis((self.ttl_ms / 3).max(1))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Deregistration {
+    pub address: String, //as in `Node::address`
+}
+
+//whether a request carries the token, never true when none is configured
+pub fn authorized(headers: &HeaderMap, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return false;
+    };
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|sent| same_token(sent, token))
+}
+
+//compared as macs keyed with the token, the check takes as long however much
+//of the token a guess gets right, and whatever its length
+fn same_token(sent: &str, token: &str) -> bool {
+    let mac = |value: &str| {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(token.as_bytes()).expect("hmac accepts any key length");
+        mac.update(value.as_bytes());
+        mac
+    };
+    mac(sent)
+        .verify_slice(&mac(token).finalize().into_bytes())
+        .is_ok()
+}
+
+fn url(balancer: &str) -> String {
+    format!("http://{}{}", balancer, PATH)
+}
+
+//`balancer` is the admin address, like 10.0.0.1:3002
+pub async fn register(
+    client: &Client,
+    balancer: &str,
+    token: Option<&str>,
+    registration: &Registration,
+) -> Result<Lease, String> {
+    let mut request = client.post(url(balancer)).json(registration);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(failure(response).await);
+    }
+    response.json().await.map_err(|e| e.to_string())
+}
+
+pub async fn deregister(
+    client: &Client,
+    balancer: &str,
+    token: Option<&str>,
+    address: &str,
+) -> Result<(), String> {
+    let deregistration = Deregistration {
+        address: address.to_string(),
+    };
+    let mut request = client.delete(url(balancer)).json(&deregistration);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(failure(response).await);
+    }
+    Ok(())
+}
+
+async fn failure(response: reqwest::Response) -> String {
+    let status = response.status();
+    let reason = response.text().await.unwrap_or_default();
+    format!("{} {}", status, reason)
+}
+
+//keeps the latest registration alive on one balancer: posts it whenever it
+//changes and again before each lease runs out, until the sender is dropped
+pub async fn keep_registered(
+    client: Client,
+    balancer: String,
+    token: Option<String>,
+    mut registration: watch::Receiver<Registration>,
+) {
+    let mut registered = false;
+    loop {
+        let current = registration.borrow_and_update().clone();
+        let address = current.node.address();
+        let wait = match register(&client, &balancer, token.as_deref(), &current).await {
+            Ok(lease) => {
+                if registered {
+                    debug!("renewed {} with {}", address, balancer);
+                } else {
+                    info!(
+                        "registered {} at {} with {}",
+                        current.name, address, balancer
+                    );
+                }
+                registered = true;
+                lease.renew_after()
+            }
+            Err(e) => {
+                warn!(
+                    "failed to register {} with {}: {}",
+                    current.name, balancer, e
+                );
+                registered = false;
+                RETRY_INTERVAL
+            }
+        };
+        tokio::select! {
+            _ = sleep(wait) => {}
+            changed = registration.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+//takes the node out of rotation right away instead of when its lease runs out
+pub async fn deregister_all(
+    client: &Client,
+    balancers: &[String],
+    token: Option<&str>,
+    address: &str,
+) {
+    for balancer in balancers {
+        match deregister(client, balancer, token, address).await {
+            Ok(()) => info!("deregistered {} from {}", address, balancer),
+            Err(e) => warn!("failed to deregister {} from {}: {}", address, balancer, e),
+        }
+    }
+}
+
+//resolves on ctrl-c, or on SIGTERM where there is one
+pub async fn shutdown() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn token_must_match_when_configured() {
+        let mut headers = HeaderMap::new();
+        assert!(!authorized(&headers, None));
+        assert!(!authorized(&headers, Some("secret")));
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer wrong"));
+        assert!(!authorized(&headers, Some("secret")));
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer secre"));
+        assert!(!authorized(&headers, Some("secret")));
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+        assert!(authorized(&headers, Some("secret")));
+        assert!(!authorized(&headers, None));
+    }
+
+    #[test]
+    fn registrations_carry_node_settings() {
+        let registration: Registration = serde_json::from_str(
+            r#"{"name": "orders", "node": {"url": "10.0.0.5:8081", "agent_port": 3005, "draining": true}}"#,
+        )
+        .unwrap();
+        assert_eq!(registration.node.address(), "10.0.0.5:8081");
+        assert_eq!(registration.node.agent_port, 3005);
+        assert!(registration.node.draining);
+        let json = serde_json::to_string(&registration).unwrap();
+        let back: Registration = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.node, registration.node);
+    }
+
+    #[test]
+    fn leases_are_renewed_early() {
+        let lease = Lease { ttl_ms: 30000 };
+        assert_eq!(lease.renew_after(), Duration::from_secs(10));
+        assert_eq!(Lease { ttl_ms: 1 }.renew_after(), Duration::from_millis(1));
+    }
+}
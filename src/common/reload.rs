@@ -0,0 +1,155 @@
+//tells the balancer when to read its config again: on SIGHUP, or when the
+//config file changes on disk
+use crate::config::loadbalancer_config::LoadBalancerConfig;
+use log::{info, warn};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+
+//how often the file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+//reads and validates the config again, errors are ready to be logged
+pub type LoadConfig = Box<dyn Fn() -> Result<LoadBalancerConfig, Vec<String>> + Send + Sync>;
+
+pub struct Reload {
+    pub path: Option<PathBuf>, //watched for changes, only SIGHUP works without it
+    pub load: LoadConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    Signal,
+    FileChanged,
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let string = match self {
+            Trigger::Signal => "SIGHUP",
+            Trigger::FileChanged => "config file changed",
+        };
+        f.write_str(string)
+    }
+}
+
+type Stamp = Option<(SystemTime, u64)>;
+
+fn stamp(path: &Path) -> Stamp {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+//editors often write a file in several steps, so a change only counts once the
+//file looks the same on two polls in a row
+struct FileWatch {
+    applied: Stamp, //the version last reported
+    seen: Stamp,    //the version found on the previous poll
+}
+
+impl FileWatch {
+    fn new(stamp: Stamp) -> Self {
+        FileWatch {
+            applied: stamp,
+            seen: stamp,
+        }
+    }
+
+    //true when the file settled on a version that was not reported yet
+    fn poll(&mut self, stamp: Stamp) -> bool {
+        let settled = stamp == self.seen;
+        self.seen = stamp;
+        //a deleted file is not a new config, it is reported once it is back
+        if settled && stamp.is_some() && stamp != self.applied {
+            self.applied = stamp;
+            return true;
+        }
+        false
+    }
+}
+
+async fn watch_file(path: PathBuf, triggers: mpsc::Sender<Trigger>) {
+    info!("watching {} for changes", path.display());
+    let mut watch = FileWatch::new(stamp(&path));
+    loop {
+        sleep(POLL_INTERVAL).await;
+        if watch.poll(stamp(&path)) && triggers.send(Trigger::FileChanged).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn watch_signal(triggers: mpsc::Sender<Trigger>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("reload on SIGHUP is off: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        if triggers.send(Trigger::Signal).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn watch_signal(_triggers: mpsc::Sender<Trigger>) {}
+
+//a trigger that arrives while a reload is queued is folded into it
+pub fn triggers(path: Option<PathBuf>) -> mpsc::Receiver<Trigger> {
+    let (sender, receiver) = mpsc::channel(1);
+    tokio::spawn(watch_signal(sender.clone()));
+    if let Some(path) = path {
+        tokio::spawn(watch_file(path, sender));
+    }
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64, len: u64) -> Stamp {
+        Some((SystemTime::UNIX_EPOCH + Duration::from_secs(secs), len))
+    }
+
+    #[test]
+    fn unchanged_file_does_not_fire() {
+        let mut watch = FileWatch::new(at(1, 10));
+        assert!(!watch.poll(at(1, 10)));
+        assert!(!watch.poll(at(1, 10)));
+    }
+
+    #[test]
+    fn change_fires_once_it_settles() {
+        let mut watch = FileWatch::new(at(1, 10));
+        assert!(!watch.poll(at(2, 4)));
+        assert!(!watch.poll(at(2, 12)));
+        assert!(watch.poll(at(2, 12)));
+        assert!(!watch.poll(at(2, 12)));
+    }
+
+    #[test]
+    fn replaced_file_fires_when_it_is_back() {
+        let mut watch = FileWatch::new(at(1, 10));
+        assert!(!watch.poll(None));
+        assert!(!watch.poll(None));
+        assert!(!watch.poll(at(3, 10)));
+        assert!(watch.poll(at(3, 10)));
+    }
+
+    #[test]
+    fn reverting_to_the_applied_version_does_not_fire() {
+        let mut watch = FileWatch::new(at(1, 10));
+        assert!(!watch.poll(at(2, 10)));
+        assert!(!watch.poll(at(1, 10)));
+        assert!(!watch.poll(at(1, 10)));
+    }
+}
@@ -0,0 +1,124 @@
+//retry policy for proxied requests
+//only idempotent requests are retried, each retry waits a jittered backoff,
+//and a cluster wide budget caps retries to a share of the requests in flight
+//so a struggling cluster does not get buried under its own retries
+use crate::config::loadbalancer_config::RetryConfig;
+use axum::http::{HeaderMap, Method};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+//keeps a counter raised until dropped
+pub struct CountGuard {
+    counter: Arc<AtomicUsize>,
+}
+
+impl CountGuard {
+    fn acquire(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        CountGuard {
+            counter: Arc::clone(counter),
+        }
+    }
+}
+
+impl Drop for CountGuard {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Default)]
+pub struct RetryBudget {
+    requests: Arc<AtomicUsize>, //requests in flight
+    retries: Arc<AtomicUsize>,  //retries in flight
+}
+
+impl RetryBudget {
+    pub fn request(&self) -> CountGuard {
+        CountGuard::acquire(&self.requests)
+    }
+
+    //reserves a retry when the budget has room for one
+    pub fn try_retry(&self, cfg: &RetryConfig) -> Option<CountGuard> {
+        let requests = self.requests.load(Ordering::SeqCst);
+        let allowed =
+            (requests * cfg.budget_percent as usize / 100).max(cfg.min_concurrent_retries);
+        let retries = self.retries.fetch_add(1, Ordering::SeqCst);
+        let guard = CountGuard {
+            counter: Arc::clone(&self.retries),
+        };
+        (retries < allowed).then_some(guard)
+    }
+}
+
+//idempotent methods, or any method carrying the idempotency header
+pub fn is_retryable(method: &Method, headers: &HeaderMap, cfg: &RetryConfig) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS
+    ) || (!cfg.idempotency_header.is_empty()
+        && headers.contains_key(cfg.idempotency_header.as_str()))
+}
+
+//full jitter: anywhere between zero and the capped exponential backoff
+pub fn backoff(retry: u32, cfg: &RetryConfig) -> Duration {
+    let ceiling = cfg
+        .backoff_base_ms
+        .saturating_mul(1 << retry.min(20))
+        .min(cfg.backoff_max_ms);
+    Duration::from_millis(rand::random_range(0..=ceiling))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn only_idempotent_requests_are_retryable() {
+        let cfg = RetryConfig::default();
+        let headers = HeaderMap::new();
+        assert!(is_retryable(&Method::GET, &headers, &cfg));
+        assert!(is_retryable(&Method::DELETE, &headers, &cfg));
+        assert!(!is_retryable(&Method::POST, &headers, &cfg));
+        assert!(!is_retryable(&Method::PATCH, &headers, &cfg));
+    }
+
+    #[test]
+    fn idempotency_header_makes_post_retryable() {
+        let cfg = RetryConfig::default();
+        let mut headers = HeaderMap::new();
+        headers.insert("idempotency-key", HeaderValue::from_static("abc"));
+        assert!(is_retryable(&Method::POST, &headers, &cfg));
+    }
+
+    #[test]
+    fn budget_caps_concurrent_retries() {
+        let cfg = RetryConfig {
+            budget_percent: 20,
+            min_concurrent_retries: 1,
+            ..RetryConfig::default()
+        };
+        let budget = RetryBudget::default();
+        let _requests: Vec<CountGuard> = (0..10).map(|_| budget.request()).collect();
+        let first = budget.try_retry(&cfg).unwrap();
+        let _second = budget.try_retry(&cfg).unwrap();
+        assert!(budget.try_retry(&cfg).is_none());
+        drop(first);
+        assert!(budget.try_retry(&cfg).is_some());
+    }
+
+    #[test]
+    fn backoff_stays_under_the_cap() {
+        let cfg = RetryConfig {
+            backoff_base_ms: 10,
+            backoff_max_ms: 50,
+            ..RetryConfig::default()
+        };
+        for retry in 0..10 {
+            assert!(backoff(retry, &cfg) <= Duration::from_millis(50));
+        }
+        assert!(backoff(0, &cfg) <= Duration::from_millis(10));
+    }
+}
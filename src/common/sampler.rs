@@ -0,0 +1,208 @@
+//samples the host's cpu, memory and network in the background at a fixed
+//interval, so /metrics answers from memory however often it is polled. the
+//last five minutes are kept for the 1m and 5m averages
+use crate::common::network::{Counters, NetworkRates, Reader};
+use crate::common::outlier::now_millis;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use sysinfo::{CpuRefreshKind, MemoryRefreshKind, RefreshKind, System};
+use tokio::time::{sleep, Instant};
+
+const ONE_MINUTE_MS: u64 = 60_000;
+const FIVE_MINUTES_MS: u64 = 5 * ONE_MINUTE_MS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Sample {
+    pub at_ms: u64,
+    pub cpu: f32, //percent, averaged over cores
+    pub ram: f64, //fraction of total memory in use
+    pub network: NetworkRates,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Averages {
+    pub cpu: f32,
+    pub ram: f64,
+    pub download_mbps: f64,
+    pub upload_mbps: f64,
+    pub samples: usize,
+}
+
+//a ring buffer of the latest samples, the oldest drops out when it is full
+pub struct History {
+    samples: VecDeque<Sample>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        History {
+            samples: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+        }
+    }
+
+    //enough samples at `interval` to cover the longest average
+    pub fn for_interval(interval: Duration) -> Self {
+        let interval_ms = (interval.as_millis() as u64).max(1);
+        History::new(FIVE_MINUTES_MS.div_ceil(interval_ms) as usize + 1)
+    }
+
+    pub fn push(&mut self, sample: Sample) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    pub fn latest(&self) -> Option<Sample> {
+        self.samples.back().copied()
+    }
+
+    //over the samples taken in the last `window_ms`, none before the first
+    pub fn average(&self, window_ms: u64, now_ms: u64) -> Option<Averages> {
+        let since = now_ms.saturating_sub(window_ms);
+        let window: Vec<&Sample> = self.samples.iter().filter(|s| s.at_ms >= since).collect();
+        if window.is_empty() {
+            return None;
+        }
+        let count = window.len();
+        let mut averages = Averages {
+            samples: count,
+            ..Averages::default()
+        };
+        for sample in window {
+            let [download, upload] = sample.network.mbps();
+            averages.cpu += sample.cpu;
+            averages.ram += sample.ram;
+            averages.download_mbps += download;
+            averages.upload_mbps += upload;
+        }
+        averages.cpu /= count as f32;
+        averages.ram /= count as f64;
+        averages.download_mbps /= count as f64;
+        averages.upload_mbps /= count as f64;
+        Some(averages)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Trend {
+    pub one_minute: Averages,
+    pub five_minutes: Averages,
+}
+
+pub struct Sampler {
+    history: RwLock<History>,
+}
+
+impl Sampler {
+    //cpu usage is measured between two refreshes, so the interval is at
+    //least the shortest gap sysinfo can measure over
+    pub fn start(interval: Duration) -> Arc<Sampler> {
+        let interval = interval.max(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL);
+        let sampler = Arc::new(Sampler {
+            history: RwLock::new(History::for_interval(interval)),
+        });
+        tokio::spawn(run(Arc::clone(&sampler), interval));
+        sampler
+    }
+
+    pub fn latest(&self) -> Option<Sample> {
+        self.history.read().unwrap().latest()
+    }
+
+    pub fn trend(&self) -> Option<Trend> {
+        let history = self.history.read().unwrap();
+        let now = now_millis();
+        Some(Trend {
+            one_minute: history.average(ONE_MINUTE_MS, now)?,
+            five_minutes: history.average(FIVE_MINUTES_MS, now)?,
+        })
+    }
+}
+
+async fn run(sampler: Arc<Sampler>, interval: Duration) {
+    let mut system = System::new_with_specifics(
+        RefreshKind::nothing()
+            .with_cpu(CpuRefreshKind::nothing().with_cpu_usage())
+            .with_memory(MemoryRefreshKind::nothing().with_ram()),
+    );
+    let mut reader = Reader::new();
+    let mut previous: Option<(Counters, Instant)> =
+        reader.read().map(|counters| (counters, Instant::now()));
+    loop {
+        sleep(interval).await;
+        system.refresh_cpu_usage();
+        system.refresh_memory_specifics(MemoryRefreshKind::nothing().with_ram());
+        let cpus = system.cpus();
+        let cpu = cpus.iter().map(|cpu| cpu.cpu_usage()).sum::<f32>() / cpus.len().max(1) as f32;
+        let ram = system.used_memory() as f64 / system.total_memory().max(1) as f64;
+
+        let now = Instant::now();
+        let counters = reader.read();
+        let network = match (&previous, &counters) {
+            (Some((before, at)), Some(after)) => NetworkRates::between(before, after, now - *at),
+            _ => NetworkRates::default(),
+        };
+        if let Some(counters) = counters {
+            previous = Some((counters, now));
+        }
+
+        sampler.history.write().unwrap().push(Sample {
+            at_ms: now_millis(),
+            cpu,
+            ram,
+            network,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(at_ms: u64, cpu: f32) -> Sample {
+        Sample {
+            at_ms,
+            cpu,
+            ram: 0.5,
+            network: NetworkRates {
+                rx_bytes: 125_000.0,
+                ..NetworkRates::default()
+            },
+        }
+    }
+
+    #[test]
+    fn full_history_drops_the_oldest() {
+        let mut history = History::new(2);
+        history.push(sample(1, 10.0));
+        history.push(sample(2, 20.0));
+        history.push(sample(3, 30.0));
+        assert_eq!(history.samples.len(), 2);
+        assert_eq!(history.samples[0].at_ms, 2);
+        assert_eq!(history.latest().unwrap().cpu, 30.0);
+    }
+
+    #[test]
+    fn averages_cover_their_window() {
+        let mut history = History::for_interval(Duration::from_secs(1));
+        assert_eq!(history.capacity, 301);
+        assert!(history.average(ONE_MINUTE_MS, 0).is_none());
+        history.push(sample(0, 90.0));
+        history.push(sample(200_000, 10.0));
+        history.push(sample(250_000, 20.0));
+
+        let minute = history.average(ONE_MINUTE_MS, 250_000).unwrap();
+        assert_eq!(minute.samples, 2);
+        assert_eq!(minute.cpu, 15.0);
+        assert_eq!(minute.ram, 0.5);
+        assert_eq!(minute.download_mbps, 1.0);
+        let five = history.average(FIVE_MINUTES_MS, 300_000).unwrap();
+        assert_eq!(five.samples, 3);
+        assert_eq!(five.cpu, 40.0);
+    }
+}
@@ -0,0 +1,65 @@
+//serves an axum router with our own accept loop, which unlike `axum::serve`
+//lets us bound how long a client may take to send its request headers. once
+//`shutdown` completes no new connections are taken, and the open ones get
+//`drain` to finish the requests they are serving
+use axum::{extract::ConnectInfo, Router};
+use hyper::{body::Incoming, service::service_fn, Request};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::GracefulShutdown;
+use log::{debug, error, info, warn};
+use std::future::Future;
+use std::pin::pin;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+use tower::ServiceExt;
+
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    header_read_timeout: Duration,
+    drain: Duration,
+    shutdown: impl Future<Output = ()>,
+) {
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(header_read_timeout);
+    let graceful = GracefulShutdown::new();
+    let mut shutdown = pin!(shutdown);
+    loop {
+        let (stream, address) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(connection) => connection,
+                Err(e) => {
+                    error!("failed to accept connection: {}", e);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        let app = app.clone();
+        //handlers read the client address through `ConnectInfo`
+        let service = service_fn(move |mut req: Request<Incoming>| {
+            req.extensions_mut().insert(ConnectInfo(address));
+            app.clone().oneshot(req)
+        });
+        let connection = builder
+            .serve_connection_with_upgrades(TokioIo::new(stream), service)
+            .into_owned();
+        let connection = graceful.watch(connection);
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("connection from {} closed: {}", address, e);
+            }
+        });
+    }
+
+    drop(listener);
+    info!("draining open connections");
+    if timeout(drain, graceful.shutdown()).await.is_err() {
+        warn!("connections still open after {:?}, closing them", drain);
+    }
+}
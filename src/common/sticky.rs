@@ -0,0 +1,66 @@
+//signed affinity cookie values for sticky sessions
+//a value looks like `<expires>.<signature>.<node ip>`, where the signature is a
+//hmac-sha256 over the expiry and node so clients cannot pick their own backend
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn signature(secret: &[u8], expires: u64, node: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("hmac accepts any key length");
+    mac.update(format!("{}.{}", expires, node).as_bytes());
+    mac
+}
+
+pub fn sign(secret: &[u8], node: &str, expires: u64) -> String {
+    let tag = signature(secret, expires, node).finalize().into_bytes();
+    let hex: String = tag.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}.{}.{}", expires, hex, node)
+}
+
+//returns the node named by a valid, unexpired cookie value
+pub fn verify(secret: &[u8], value: &str, now: u64) -> Option<String> {
+    let mut fields = value.splitn(3, '.');
+    let expires: u64 = fields.next()?.parse().ok()?;
+    let hex = fields.next()?;
+    let node = fields.next()?;
+    if expires < now || hex.len() % 2 != 0 {
+        return None;
+    }
+    let tag = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    signature(secret, expires, node)
+        .verify_slice(&tag)
+        .ok()
+        .map(|_| node.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"secret";
+
+    #[test]
+    fn signed_value_round_trips() {
+        let value = sign(SECRET, "10.0.0.1", 100);
+        assert_eq!(verify(SECRET, &value, 50), Some("10.0.0.1".to_string()));
+    }
+
+    #[test]
+    fn expired_value_is_rejected() {
+        let value = sign(SECRET, "10.0.0.1", 100);
+        assert_eq!(verify(SECRET, &value, 101), None);
+    }
+
+    #[test]
+    fn tampered_value_is_rejected() {
+        let value = sign(SECRET, "10.0.0.1", 100);
+        let forged = value.replace("10.0.0.1", "10.0.0.2");
+        assert_eq!(verify(SECRET, &forged, 50), None);
+        assert_eq!(verify(b"other", &value, 50), None);
+        assert_eq!(verify(SECRET, "garbage", 50), None);
+    }
+}
@@ -1,8 +1,10 @@
+use crate::config::cluster_config::{DatabaseConfig, LoggingConfig};
 use log::info;
-use log4rs;
-use serde_yaml;
+use log4rs::{self, config::RawConfig};
+use serde_yaml::{self, Value};
 use sqlx::PgPool;
 use std::env;
+use std::path::Path;
 
 // ERROR
 // WARN
@@ -14,15 +16,43 @@ use std::env;
 // error!("Goes to console, file and rolling file");
 // trace!("Doesn't go to console as it is filtered out");
 
-pub fn log_init() {
+pub fn log_init(cfg: &LoggingConfig) {
     let config_str = include_str!("log_config.yml"); //env
-    let config = serde_yaml::from_str(config_str).expect("error parsing log config");
+    let mut config: Value = serde_yaml::from_str(config_str).expect("error parsing log config");
 
+    if let Some(level) = &cfg.level {
+        config["root"]["level"] = level.as_str().into();
+        config["appenders"]["stdout"]["filters"][0]["level"] = level.as_str().into();
+    }
+    if let Some(directory) = &cfg.directory {
+        let appenders = config["appenders"].as_mapping_mut().unwrap();
+        for (_, appender) in appenders.iter_mut() {
+            relocate(appender.get_mut("path"), directory);
+            let pattern = appender
+                .get_mut("policy")
+                .and_then(|policy| policy.get_mut("roller"))
+                .and_then(|roller| roller.get_mut("pattern"));
+            relocate(pattern, directory);
+        }
+    }
+    let config: RawConfig = serde_yaml::from_value(config).expect("error parsing log config");
     log4rs::init_raw_config(config).expect("logger failed to initialize");
 }
 
-pub async fn db_init() -> Result<PgPool, sqlx::Error> {
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+//moves a log file from the default log/ directory into `directory`
+fn relocate(path: Option<&mut Value>, directory: &Path) {
+    if let Some(path) = path {
+        if let Some(file) = path.as_str().and_then(|p| p.strip_prefix("log/")) {
+            *path = directory.join(file).to_string_lossy().into_owned().into();
+        }
+    }
+}
+
+pub async fn db_init(cfg: &DatabaseConfig) -> Result<PgPool, sqlx::Error> {
+    let database_url = match &cfg.url {
+        Some(url) => url.clone(),
+        None => env::var("DATABASE_URL").expect("DATABASE_URL must be set"),
+    };
     let pool = PgPool::connect(&database_url).await?;
     sqlx::migrate!("./migrations").run(&pool).await?;
     info!("connected to database");
@@ -0,0 +1,580 @@
+//the config file passed with --config, yaml or toml depending on its extension
+//cluster.example.yaml documents every field
+use crate::config::loadbalancer_config::LoadBalancerConfig;
+use crate::config::locate::{line_of, toml_lines, yaml_lines};
+use crate::config::microserver_config::MicroServerConfig;
+use crate::config::server_config::ServerConfig;
+use log::LevelFilter;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use toml_edit::{DocumentMut, Item, Table};
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub load_balancer: Option<LoadBalancerConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node: Option<ServerConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub micro_server: Option<MicroServerConfig>,
+    #[serde(default)]
+    pub database: DatabaseConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>, //DATABASE_URL is used when unset
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<String>, //off, error, warn, info, debug or trace
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub directory: Option<PathBuf>, //where the log files go, ./log when unset
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ConfigError {
+    pub path: String, //field path, empty for the whole file
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        if !self.path.is_empty() {
+            write!(f, "{}: ", self.path)?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+impl ConfigError {
+    fn file(message: String) -> Self {
+        ConfigError {
+            path: String::new(),
+            line: None,
+            message,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    fn of(path: &Path) -> Result<Format, ConfigError> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml" | "yml") => Ok(Format::Yaml),
+            Some("toml") => Ok(Format::Toml),
+            _ => Err(ConfigError::file(format!(
+                "{} should end in .yaml, .yml or .toml",
+                path.display()
+            ))),
+        }
+    }
+}
+
+impl ClusterConfig {
+    pub fn validate(&self) -> Vec<(String, String)> {
+        let mut errors = vec![];
+        if self.load_balancer.is_none() && self.node.is_none() && self.micro_server.is_none() {
+            errors.push((
+                String::new(),
+                "needs a load_balancer, node or micro_server section".to_string(),
+            ));
+        }
+        let sections = [
+            (
+                "load_balancer",
+                self.load_balancer.as_ref().map(|c| c.validate()),
+            ),
+            ("node", self.node.as_ref().map(|c| c.validate())),
+            (
+                "micro_server",
+                self.micro_server.as_ref().map(|c| c.validate()),
+            ),
+        ];
+        for (section, section_errors) in sections {
+            for (path, message) in section_errors.unwrap_or_default() {
+                errors.push((format!("{}.{}", section, path), message));
+            }
+        }
+        if let Some(url) = &self.database.url {
+            if !url.starts_with("postgres://") && !url.starts_with("postgresql://") {
+                errors.push((
+                    "database.url".to_string(),
+                    "must be a postgres:// url".to_string(),
+                ));
+            }
+        }
+        if let Some(level) = &self.logging.level {
+            if level.parse::<LevelFilter>().is_err() {
+                errors.push((
+                    "logging.level".to_string(),
+                    "must be off, error, warn, info, debug or trace".to_string(),
+                ));
+            }
+        }
+        errors
+    }
+}
+
+//what a balancer or node agent binds: every `listen` address when there is one,
+//otherwise `ip` with `port`, call after validating
+pub fn listen_addresses(ip: &str, port: u16, listen: &[String]) -> Vec<SocketAddr> {
+    if listen.is_empty() {
+        return ip
+            .parse::<IpAddr>()
+            .map(|ip| vec![SocketAddr::new(ip, port)])
+            .unwrap_or_default();
+    }
+    listen
+        .iter()
+        .filter_map(|address| address.parse().ok())
+        .collect()
+}
+
+//every problem with the port and listen fields as (field path, message)
+pub fn validate_listen(port: u16, listen: &[String]) -> Vec<(String, String)> {
+    let mut errors = vec![];
+    if port == 0 {
+        errors.push(("port".to_string(), "must be greater than 0".to_string()));
+    }
+    for (i, address) in listen.iter().enumerate() {
+        let path = format!("listen[{}]", i);
+        match address.parse::<SocketAddr>() {
+            Ok(parsed) if listen[..i].iter().any(|other| other.parse() == Ok(parsed)) => {
+                errors.push((path, "the same address is listed twice".to_string()))
+            }
+            Ok(_) => {}
+            Err(_) => errors.push((
+                path,
+                "must be an address with a port, like 0.0.0.0:3000 or [::]:3000".to_string(),
+            )),
+        }
+    }
+    errors
+}
+
+//reads and checks a config file, returning every problem found
+pub fn load(path: &Path) -> Result<ClusterConfig, Vec<ConfigError>> {
+    let format = Format::of(path).map_err(|e| vec![e])?;
+    let source = fs::read_to_string(path).map_err(|e| vec![ConfigError::file(e.to_string())])?;
+    parse(&source, format)
+}
+
+fn parse(source: &str, format: Format) -> Result<ClusterConfig, Vec<ConfigError>> {
+    let config = deserialize(source, format).map_err(|e| vec![e])?;
+    let lines = match format {
+        Format::Yaml => yaml_lines(source),
+        Format::Toml => toml_lines(source),
+    };
+    let mut errors: Vec<ConfigError> = config
+        .validate()
+        .into_iter()
+        .map(|(path, message)| ConfigError {
+            line: line_of(&lines, &path),
+            path,
+            message,
+        })
+        .collect();
+    errors.sort_by_key(|e| e.line);
+    if errors.is_empty() {
+        Ok(config)
+    } else {
+        Err(errors)
+    }
+}
+
+//serde stops at the first bad field, so this reports at most one error
+fn deserialize(source: &str, format: Format) -> Result<ClusterConfig, ConfigError> {
+    //"." is how serde_path_to_error names the root
+    let field = |path: String| if path == "." { String::new() } else { path };
+    match format {
+        Format::Yaml => {
+            let deserializer = serde_yaml::Deserializer::from_str(source);
+            serde_path_to_error::deserialize(deserializer).map_err(|e| {
+                let line = e.inner().location().map(|l| l.line());
+                //the message repeats the location, which is reported on its own
+                let message = e.inner().to_string();
+                let message = message.split(" at line ").next().unwrap_or_default();
+                ConfigError {
+                    path: field(e.path().to_string()),
+                    line,
+                    message: message.to_string(),
+                }
+            })
+        }
+        Format::Toml => {
+            let deserializer = toml::Deserializer::new(source);
+            serde_path_to_error::deserialize(deserializer).map_err(|e| {
+                let line = e
+                    .inner()
+                    .span()
+                    .map(|span| source[..span.start].matches('\n').count() + 1);
+                ConfigError {
+                    path: field(e.path().to_string()),
+                    line,
+                    message: e.inner().message().to_string(),
+                }
+            })
+        }
+    }
+}
+
+//writes one top level section of a config file, creating the file when missing.
+//everything outside the section is kept as written, comments included
+pub fn update(path: &Path, section: &str, value: &impl Serialize) -> Result<(), String> {
+    let format = Format::of(path).map_err(|e| e.to_string())?;
+    let source = if path.exists() {
+        fs::read_to_string(path).map_err(|e| e.to_string())?
+    } else {
+        String::new()
+    };
+    let contents = replace_section(&source, format, section, value)?;
+    fs::write(path, contents).map_err(|e| e.to_string())
+}
+
+fn replace_section(
+    source: &str,
+    format: Format,
+    section: &str,
+    value: &impl Serialize,
+) -> Result<String, String> {
+    let contents = match format {
+        Format::Yaml => replace_yaml_section(source, section, value)?,
+        Format::Toml => replace_toml_section(source, section, value)?,
+    };
+    //never leave behind a file that no longer loads
+    deserialize(&contents, format)
+        .map_err(|e| format!("the updated file would not load: {}", e))?;
+    Ok(contents)
+}
+
+//the section runs from its key to the next top level key, less the blank and
+//comment lines right before that key, which describe the next section
+fn replace_yaml_section(
+    source: &str,
+    section: &str,
+    value: &impl Serialize,
+) -> Result<String, String> {
+    let rendered =
+        serde_yaml::to_string(&BTreeMap::from([(section, value)])).map_err(|e| e.to_string())?;
+    let lines: Vec<&str> = source.split_inclusive('\n').collect();
+    let is_key = |line: &str| !line.starts_with([' ', '\t', '#', '\r', '\n']);
+    let start = lines.iter().position(|line| {
+        is_key(line)
+            && line
+                .strip_prefix(section)
+                .is_some_and(|rest| rest.trim_start().starts_with(':'))
+    });
+    let Some(start) = start else {
+        let mut contents = source.to_string();
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        contents.push_str(&rendered);
+        return Ok(contents);
+    };
+    let mut end = lines[start + 1..]
+        .iter()
+        .position(|line| is_key(line))
+        .map_or(lines.len(), |i| start + 1 + i);
+    while end > start + 1 && (lines[end - 1].trim().is_empty() || lines[end - 1].starts_with('#')) {
+        end -= 1;
+    }
+    Ok(format!(
+        "{}{}{}",
+        lines[..start].concat(),
+        rendered,
+        lines[end..].concat()
+    ))
+}
+
+//the section's tables take the place of the old ones, keeping the comments
+//written above its header
+fn replace_toml_section(
+    source: &str,
+    section: &str,
+    value: &impl Serialize,
+) -> Result<String, String> {
+    let mut document: DocumentMut = source.parse().map_err(|e| format!("{}", e))?;
+    let rendered =
+        toml::to_string_pretty(&BTreeMap::from([(section, value)])).map_err(|e| e.to_string())?;
+    let rendered: DocumentMut = rendered.parse().map_err(|e| format!("{}", e))?;
+    let mut item = rendered[section].clone();
+    let old = document.get(section).and_then(Item::as_table);
+    //tables are written out in position order, the whole section goes where
+    //the old one was or after everything else
+    let position = old.and_then(Table::position).unwrap_or(usize::MAX);
+    if let Some(table) = item.as_table_mut() {
+        if let Some(old) = old {
+            *table.decor_mut() = old.decor().clone();
+        }
+        place(table, position);
+    }
+    document[section] = item;
+    Ok(document.to_string())
+}
+
+fn place(table: &mut Table, position: usize) {
+    table.set_position(position);
+    for (_, item) in table.iter_mut() {
+        match item {
+            Item::Table(table) => place(table, position),
+            Item::ArrayOfTables(tables) => tables.iter_mut().for_each(|t| place(t, position)),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(source: &str, format: Format) -> Vec<String> {
+        parse(source, format)
+            .unwrap_err()
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn example_config_is_valid() {
+        let example = include_str!("../../cluster.example.yaml");
+        let config = parse(example, Format::Yaml).unwrap();
+        assert!(config.load_balancer.is_some());
+        assert!(config.node.is_some());
+    }
+
+    #[test]
+    fn unknown_yaml_fields_report_path_and_line() {
+        let source =
+            "load_balancer:\n  ip: 10.0.0.1\n  nodes: [10.0.0.2]\n  retry:\n    max_retry: 3\n";
+        let errors = errors(source, Format::Yaml);
+        assert_eq!(errors.len(), 1);
+        assert!(
+            errors[0].starts_with("line 5: load_balancer.retry"),
+            "{}",
+            errors[0]
+        );
+        assert!(errors[0].contains("unknown field `max_retry`"));
+    }
+
+    #[test]
+    fn bad_toml_values_report_path_and_line() {
+        let source = "[load_balancer]\nip = \"10.0.0.1\"\nnodes = [\"10.0.0.2\"]\n\n[load_balancer.timeouts]\nconnect_ms = \"soon\"\n";
+        let errors = errors(source, Format::Toml);
+        assert_eq!(errors.len(), 1);
+        assert!(
+            errors[0].starts_with("line 6: load_balancer.timeouts.connect_ms"),
+            "{}",
+            errors[0]
+        );
+    }
+
+    #[test]
+    fn node_tables_are_strict() {
+        let source =
+            "load_balancer:\n  ip: 10.0.0.1\n  nodes:\n    - url: 10.0.0.2\n      wieght: 2\n";
+        let errors = errors(source, Format::Yaml);
+        assert!(
+            errors[0].starts_with("line 5: load_balancer.nodes[0]"),
+            "{}",
+            errors[0]
+        );
+        assert!(errors[0].contains("unknown field `wieght`"));
+    }
+
+    #[test]
+    fn every_semantic_error_is_reported_with_its_line() {
+        let source = "\
+load_balancer:
+  ip: not-an-ip
+  nodes:
+    - 10.0.0.2
+    - 10.0.0.2
+  circuit_breaker:
+    failure_rate_percent: 150
+database:
+  url: mysql://localhost
+";
+        assert_eq!(
+            errors(source, Format::Yaml),
+            vec![
+                "line 2: load_balancer.ip: must be an ip address",
+                "line 5: load_balancer.nodes[1]: the same node is listed twice",
+                "line 7: load_balancer.circuit_breaker.failure_rate_percent: must be between 1 and 100",
+                "line 9: database.url: must be a postgres:// url",
+            ]
+        );
+    }
+
+    #[test]
+    fn listen_addresses_need_a_port() {
+        let source = "\
+load_balancer:
+  ip: ::1
+  port: 0
+  listen:
+    - 0.0.0.0
+    - '[::]:3000'
+    - '[::]:3000'
+  admin_listen: '[::]:3000'
+  nodes: [10.0.0.2]
+";
+        assert_eq!(
+            errors(source, Format::Yaml),
+            vec![
+                "line 3: load_balancer.port: must be greater than 0",
+                "line 5: load_balancer.listen[0]: must be an address with a port, like 0.0.0.0:3000 or [::]:3000",
+                "line 7: load_balancer.listen[2]: the same address is listed twice",
+                "line 8: load_balancer.admin_listen: must not be one of the traffic addresses",
+            ]
+        );
+    }
+
+    #[test]
+    fn toml_semantic_errors_point_at_the_field() {
+        let source = "\
+[node]
+ip = \"10.0.0.3\"
+listener = []
+loadbalancer_ip = [\"10.0.0.1\", \"lb\"]
+
+[logging]
+level = \"loud\"
+";
+        assert_eq!(
+            errors(source, Format::Toml),
+            vec![
+                "line 4: node.loadbalancer_ip[1]: must be an ip address or ip:port",
+                "line 7: logging.level: must be off, error, warn, info, debug or trace",
+            ]
+        );
+    }
+
+    #[test]
+    fn update_keeps_the_other_sections() {
+        let path = std::env::temp_dir().join(format!("cluster-{}.toml", std::process::id()));
+        fs::write(&path, "[logging]\nlevel = \"debug\"\n").unwrap();
+        let node = ServerConfig {
+            ip: "10.0.0.3".to_string(),
+            ..ServerConfig::default()
+        };
+        update(&path, "node", &node).unwrap();
+        let config = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(config.logging.level.as_deref(), Some("debug"));
+        assert_eq!(config.node.unwrap().ip, "10.0.0.3");
+    }
+
+    fn node(ip: &str) -> ServerConfig {
+        ServerConfig {
+            ip: ip.to_string(),
+            listener: vec![],
+            ..ServerConfig::default()
+        }
+    }
+
+    #[test]
+    fn yaml_updates_keep_comments_outside_the_section() {
+        let source = "\
+# the whole cluster
+logging:
+  level: debug   # chatty for now
+
+# the node agent
+node:
+  ip: 10.0.0.1   # replaced
+  listener: []
+  loadbalancer_ip: []
+
+# where logs go
+database:
+  url: postgres://localhost/cluster
+";
+        let updated = replace_section(source, Format::Yaml, "node", &node("10.0.0.3")).unwrap();
+        assert!(updated.starts_with("# the whole cluster\nlogging:\n  level: debug   # chatty for now\n\n# the node agent\nnode:\n"));
+        assert!(updated
+            .ends_with("\n# where logs go\ndatabase:\n  url: postgres://localhost/cluster\n"));
+        assert!(!updated.contains("replaced"));
+        let config = parse(&updated, Format::Yaml).unwrap();
+        assert_eq!(config.node.unwrap().ip, "10.0.0.3");
+
+        let added = replace_section(
+            "# only logs\nlogging: {}",
+            Format::Yaml,
+            "node",
+            &node("10.0.0.4"),
+        )
+        .unwrap();
+        assert!(added.starts_with("# only logs\nlogging: {}\nnode:\n"));
+    }
+
+    #[test]
+    fn toml_updates_keep_comments_outside_the_section() {
+        let source = "\
+# the whole cluster
+[logging]
+level = \"debug\" # chatty for now
+
+# the node agent
+[node]
+ip = \"10.0.0.1\"
+listener = []
+loadbalancer_ip = []
+
+# where logs go
+[database]
+url = \"postgres://localhost/cluster\"
+";
+        let updated = replace_section(source, Format::Toml, "node", &node("10.0.0.3")).unwrap();
+        assert!(updated.starts_with("# the whole cluster\n[logging]\nlevel = \"debug\" # chatty for now\n\n# the node agent\n[node]\n"), "{}", updated);
+        assert!(
+            updated.contains("\n# where logs go\n[database]\n"),
+            "{}",
+            updated
+        );
+        let config = parse(&updated, Format::Toml).unwrap();
+        assert_eq!(config.node.unwrap().ip, "10.0.0.3");
+        assert!(updated.find("[node]") < updated.find("[database]"));
+    }
+
+    #[test]
+    fn example_config_keeps_its_comments_when_updated() {
+        let example = include_str!("../../cluster.example.yaml");
+        let node = parse(example, Format::Yaml).unwrap().node.unwrap();
+        let updated = replace_section(example, Format::Yaml, "node", &node).unwrap();
+        assert!(updated.contains("# the load balancer, only read by `cluster lb`"));
+        assert!(updated.contains("# the node agent, only read by `cluster node`"));
+        assert!(updated.contains("# services sharing one host"));
+        parse(&updated, Format::Yaml).unwrap();
+    }
+
+    #[test]
+    fn format_comes_from_the_extension() {
+        assert_eq!(Format::of(Path::new("a.yml")), Ok(Format::Yaml));
+        assert_eq!(Format::of(Path::new("a.toml")), Ok(Format::Toml));
+        assert!(Format::of(Path::new("a.json")).is_err());
+    }
+}
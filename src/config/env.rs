@@ -0,0 +1,243 @@
+//CLUSTER_* environment variables, applied over the config file and under the
+//command line flags so the same image can run in every environment
+//  CLUSTER_LOAD_BALANCER__HEALTH_CHECK__INTERVAL_MS=500
+//  CLUSTER_NODE__LOADBALANCER_IP=[10.0.0.1, 10.0.0.2]
+//  CLUSTER_DATABASE__URL=postgres://postgres:password@db/cluster
+//a double underscore separates the fields, values are read as yaml so numbers,
+//lists and maps work, quote a string that would read as a number. other
+//CLUSTER_* variables, like the CLUSTER_SERVICE_HOST kubernetes sets for a
+//service named cluster, are left alone
+use crate::config::cluster_config::{ClusterConfig, ConfigError};
+use serde_yaml::{Mapping, Value};
+
+pub const PREFIX: &str = "CLUSTER_";
+
+//the config sections, an override names one of them first
+const SECTIONS: [&str; 5] = [
+    "LOAD_BALANCER",
+    "NODE",
+    "MICRO_SERVER",
+    "DATABASE",
+    "LOGGING",
+];
+
+struct Override {
+    name: String,
+    path: String, //the field it sets, as in config errors
+}
+
+fn is_override(name: &str) -> bool {
+    let Some(rest) = name.strip_prefix(PREFIX) else {
+        return false;
+    };
+    SECTIONS.iter().any(|section| {
+        rest.strip_prefix(section)
+            .is_some_and(|fields| fields.starts_with("__"))
+    })
+}
+
+//the config with every override in `vars` applied
+pub fn apply(
+    config: &ClusterConfig,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<ClusterConfig, Vec<ConfigError>> {
+    let mut vars: Vec<(String, String)> = vars
+        .into_iter()
+        .filter(|(name, _)| is_override(name))
+        .collect();
+    if vars.is_empty() {
+        return Ok(config.clone());
+    }
+    //later variables win when two set the same field, so the order must not
+    //depend on the environment
+    vars.sort();
+
+    let mut tree = serde_yaml::to_value(config).map_err(|e| vec![error("", e.to_string())])?;
+    let mut overrides = vec![];
+    let mut errors = vec![];
+    for (name, raw) in vars {
+        let keys: Vec<String> = name[PREFIX.len()..]
+            .split("__")
+            .map(str::to_lowercase)
+            .collect();
+        if keys.iter().any(String::is_empty) {
+            errors.push(error(
+                &name,
+                "is not a field, separate fields with __".to_string(),
+            ));
+            continue;
+        }
+        let value = serde_yaml::from_str(&raw).unwrap_or(Value::String(raw));
+        match set(&mut tree, &keys, value) {
+            Ok(()) => overrides.push(Override {
+                path: keys.join("."),
+                name,
+            }),
+            Err(message) => errors.push(error(&name, message)),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    serde_path_to_error::deserialize(tree).map_err(|e| {
+        let path = e.path().to_string();
+        //the variable that set the field, or the section holding an unknown one
+        let name = overrides
+            .iter()
+            .rev()
+            .find(|o| path.starts_with(&o.path) || o.path.starts_with(&path))
+            .map_or(PREFIX, |o| &o.name);
+        vec![error(name, e.inner().to_string())]
+    })
+}
+
+fn error(name: &str, message: String) -> ConfigError {
+    ConfigError {
+        path: name.to_string(),
+        line: None,
+        message,
+    }
+}
+
+//sections that are not in the file yet are created on the way
+fn set(tree: &mut Value, keys: &[String], value: Value) -> Result<(), String> {
+    if tree.is_null() {
+        *tree = Value::Mapping(Mapping::new());
+    }
+    let (Some(map), Some((key, rest))) = (tree.as_mapping_mut(), keys.split_first()) else {
+        return Err("is not a field".to_string());
+    };
+    let child = map.entry(Value::String(key.clone())).or_insert(Value::Null);
+    if rest.is_empty() {
+        *child = value;
+        Ok(())
+    } else if child.is_null() || child.is_mapping() {
+        set(child, rest, value)
+    } else {
+        Err(format!("{} is not a section", key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::loadbalancer_config::{LoadBalancerConfig, Protocol};
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn file() -> ClusterConfig {
+        ClusterConfig {
+            load_balancer: Some(LoadBalancerConfig {
+                ip: "10.0.0.1".to_string(),
+                nodes: vec!["10.0.0.2".parse().unwrap()],
+                ..LoadBalancerConfig::default()
+            }),
+            ..ClusterConfig::default()
+        }
+    }
+
+    #[test]
+    fn variables_override_the_file() {
+        let config = apply(
+            &file(),
+            vars(&[
+                ("CLUSTER_LOAD_BALANCER__PROTOCOL", "LeastConnections"),
+                ("CLUSTER_LOAD_BALANCER__HEALTH_CHECK__INTERVAL_MS", "500"),
+                ("CLUSTER_LOAD_BALANCER__NODES", "[10.0.0.3, 10.0.0.4]"),
+                ("CLUSTER_LOGGING__LEVEL", "debug"),
+                ("HOME", "/root"),
+            ]),
+        )
+        .unwrap();
+        let lb = config.load_balancer.unwrap();
+        assert_eq!(lb.ip, "10.0.0.1");
+        assert!(matches!(lb.protocol, Protocol::LeastConnections));
+        assert_eq!(lb.health_check.interval_ms, 500);
+        assert_eq!(lb.health_check.timeout_ms, 1000);
+        assert_eq!(lb.nodes.len(), 2);
+        assert_eq!(config.logging.level.as_deref(), Some("debug"));
+    }
+
+    #[test]
+    fn missing_sections_are_created() {
+        let config = apply(
+            &ClusterConfig::default(),
+            vars(&[
+                ("CLUSTER_NODE__IP", "10.0.0.5"),
+                ("CLUSTER_NODE__LISTENER", "[HealthCheckListener]"),
+                ("CLUSTER_NODE__LOADBALANCER_IP", "[10.0.0.1]"),
+                (
+                    "CLUSTER_LOAD_BALANCER__STICKY_SESSION",
+                    "{cookie_name: lb, ttl_secs: 60, secret: '1234'}",
+                ),
+                ("CLUSTER_LOAD_BALANCER__IP", "10.0.0.1"),
+                ("CLUSTER_LOAD_BALANCER__NODES", "[10.0.0.2]"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.node.unwrap().loadbalancer_ip, vec!["10.0.0.1"]);
+        let sticky = config.load_balancer.unwrap().sticky_session.unwrap();
+        assert_eq!(sticky.secret.as_deref(), Some("1234"));
+    }
+
+    #[test]
+    fn bad_values_name_the_variable() {
+        let errors = apply(
+            &file(),
+            vars(&[("CLUSTER_LOAD_BALANCER__TIMEOUTS__CONNECT_MS", "soon")]),
+        )
+        .unwrap_err();
+        assert_eq!(
+            errors[0].path,
+            "CLUSTER_LOAD_BALANCER__TIMEOUTS__CONNECT_MS"
+        );
+    }
+
+    #[test]
+    fn unknown_fields_name_the_variable() {
+        let errors = apply(
+            &file(),
+            vars(&[("CLUSTER_LOAD_BALANCER__RETRY__MAX_RETRY", "3")]),
+        )
+        .unwrap_err();
+        assert_eq!(errors[0].path, "CLUSTER_LOAD_BALANCER__RETRY__MAX_RETRY");
+        assert!(errors[0].message.contains("unknown field `max_retry`"));
+    }
+
+    #[test]
+    fn variables_outside_the_sections_are_ignored() {
+        let config = apply(
+            &file(),
+            vars(&[
+                ("CLUSTER_SERVICE_HOST", "10.96.0.12"),
+                ("CLUSTER_PORT", "tcp://10.96.0.12:3000"),
+                ("CLUSTER_PORT_3000_TCP", "tcp://10.96.0.12:3000"),
+                ("CLUSTER_NODES", "[10.0.0.9]"),
+                ("CLUSTER_LOGGING", "debug"),
+            ]),
+        )
+        .unwrap();
+        let yaml = |config: &ClusterConfig| serde_yaml::to_string(config).unwrap();
+        assert_eq!(yaml(&config), yaml(&file()));
+    }
+
+    #[test]
+    fn fields_inside_values_are_rejected() {
+        let errors = apply(
+            &file(),
+            vars(&[
+                ("CLUSTER_LOAD_BALANCER__IP__HOST", "x"),
+                ("CLUSTER_LOGGING____LEVEL", "info"),
+            ]),
+        )
+        .unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].message, "ip is not a section");
+    }
+}
@@ -1,7 +1,18 @@
+use crate::config::cluster_config::{self, listen_addresses, validate_listen};
+use crate::config::server_config::DEFAULT_AGENT_PORT;
 use crate::validator::validate::validate_lb_config;
 use confy::{self, ConfyError};
 use dialoguer::{theme::ColorfulTheme, Confirm, Input, MultiSelect, Select};
 use log::{error, info};
+use reqwest::Url;
+use serde::de::{self, value::MapAccessDeserializer, MapAccess, Visitor};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
 pub enum Features {
@@ -9,64 +20,983 @@ pub enum Features {
     ApiHealthCheck,
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, Default)]
 pub enum Protocol {
+    #[default]
     RobinRound,
     LeastConnections,
     LeastResponse,
+    ResourceAware,
+    WeightedRobinRound,
+    ConsistentHash,
+}
+
+//what part of a request consistent hashing keys on
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, Default, PartialEq)]
+pub enum HashKey {
+    #[default]
+    ClientIp,
+    Header(String),
+    Cookie(String),
+    PathPrefix(usize), //number of leading path segments
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
+#[serde(try_from = "NodeEntry", into = "NodeEntry")]
+pub struct Node {
+    pub url: Url,                       //scheme, host and port of the application
+    pub agent_port: u16,                //port the node agent serves metrics on
+    pub weight: u32,                    //share of traffic relative to the other nodes
+    pub max_connections: Option<usize>, //in-flight requests cap, none for unlimited
+    pub draining: bool,                 //finishes in-flight requests but takes no new ones
+}
+
+impl Node {
+    //host name or ip, ipv6 addresses keep their brackets
+    pub fn host(&self) -> &str {
+        self.url.host_str().unwrap_or_default()
+    }
+
+    //host and application port, identifies the node across config changes
+    pub fn address(&self) -> String {
+        format!(
+            "{}:{}",
+            self.host(),
+            self.url.port_or_known_default().unwrap_or_default()
+        )
+    }
+
+    pub fn upstream_url(&self, path_and_query: &str) -> String {
+        format!(
+            "{}{}",
+            self.url.as_str().trim_end_matches('/'),
+            path_and_query
+        )
+    }
+
+    pub fn agent_url(&self, path: &str) -> String {
+        format!("http://{}:{}{}", self.host(), self.agent_port, path)
+    }
+}
+
+//older configs store nodes as bare ip strings or as tables keyed by `ip`
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum NodeEntry {
+    Address(String),
+    Node(NodeFields),
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NodeFields {
+    #[serde(alias = "ip")]
+    url: String,
+    #[serde(default = "default_agent_port")]
+    agent_port: u16,
+    #[serde(default = "default_weight")]
+    weight: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_connections: Option<usize>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    draining: bool,
+}
+
+//picks the form by hand, untagged enums would hide what is wrong with a table
+impl<'de> serde::Deserialize<'de> for NodeEntry {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntryVisitor;
+
+        impl<'de> Visitor<'de> for EntryVisitor {
+            type Value = NodeEntry;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a node address or a node table")
+            }
+
+            fn visit_str<E: de::Error>(self, address: &str) -> Result<NodeEntry, E> {
+                Ok(NodeEntry::Address(address.to_string()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<NodeEntry, A::Error> {
+                NodeFields::deserialize(MapAccessDeserializer::new(map)).map(NodeEntry::Node)
+            }
+        }
+
+        deserializer.deserialize_any(EntryVisitor)
+    }
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+//nodes are polled where their agent listens by default
+fn default_agent_port() -> u16 {
+    DEFAULT_AGENT_PORT
+}
+
+//where the balancer takes traffic unless configured, and where agents register
+//with it when it has no admin_listen
+pub const DEFAULT_PORT: u16 = 3000;
+
+fn default_port() -> u16 {
+    DEFAULT_PORT
+}
+
+//accepts a full url or a bare host with an optional port, defaulting to http
+pub fn parse_node_url(input: &str) -> Result<Url, String> {
+    let input = input.trim();
+    let with_scheme = if input.contains("://") {
+        input.to_string()
+    } else {
+        format!("http://{}", input)
+    };
+    let url =
+        Url::parse(&with_scheme).map_err(|e| format!("invalid node address {}: {}", input, e))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!("unsupported scheme {} in {}", url.scheme(), input));
+    }
+    if url.host_str().is_none_or(str::is_empty) {
+        return Err(format!("node address {} has no host", input));
+    }
+    Ok(url)
+}
+
+impl TryFrom<NodeEntry> for Node {
+    type Error = String;
+
+    fn try_from(entry: NodeEntry) -> Result<Self, Self::Error> {
+        match entry {
+            NodeEntry::Address(address) => Ok(Node {
+                url: parse_node_url(&address)?,
+                agent_port: default_agent_port(),
+                weight: default_weight(),
+                max_connections: None,
+                draining: false,
+            }),
+            NodeEntry::Node(NodeFields {
+                url,
+                agent_port,
+                weight,
+                max_connections,
+                draining,
+            }) => Ok(Node {
+                url: parse_node_url(&url)?,
+                agent_port,
+                weight,
+                max_connections,
+                draining,
+            }),
+        }
+    }
+}
+
+impl From<Node> for NodeEntry {
+    fn from(node: Node) -> Self {
+        NodeEntry::Node(NodeFields {
+            url: node.url.to_string(),
+            agent_port: node.agent_port,
+            weight: node.weight,
+            max_connections: node.max_connections,
+            draining: node.draining,
+        })
+    }
+}
+
+//active probing of every node's agent
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    pub interval_ms: u64,
+    pub timeout_ms: u64,
+    pub rise: u32, //passed probes in a row before a node is put back
+    pub fall: u32, //failed probes in a row before a node is taken out
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        HealthCheckConfig {
+            interval_ms: 1000,
+            timeout_ms: 1000,
+            rise: 2,
+            fall: 3,
+        }
+    }
+}
+
+//ejects nodes that keep failing proxied requests
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutlierDetectionConfig {
+    pub enabled: bool,
+    pub consecutive_failures: u32, //connect errors, timeouts and 5xx in a row
+    pub base_ejection_ms: u64,     //doubles with every repeat ejection
+    pub max_ejection_ms: u64,
+    pub max_ejection_percent: u32, //at least one node can always be ejected
+}
+
+impl Default for OutlierDetectionConfig {
+    fn default() -> Self {
+        OutlierDetectionConfig {
+            enabled: true,
+            consecutive_failures: 5,
+            base_ejection_ms: 30_000,
+            max_ejection_ms: 300_000,
+            max_ejection_percent: 10,
+        }
+    }
+}
+
+//per node breaker around the upstream call
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    pub enabled: bool,
+    pub max_requests: usize,       //in-flight requests per node
+    pub max_pending: usize,        //requests per node still waiting for response headers
+    pub failure_rate_percent: u32, //opens the breaker once reached within the window
+    pub min_requests: u32,         //requests needed in the window before the rate counts
+    pub window_ms: u64,
+    pub open_ms: u64,            //time an open breaker refuses requests
+    pub half_open_requests: u32, //trial requests let through after that
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            enabled: true,
+            max_requests: 1024,
+            max_pending: 256,
+            failure_rate_percent: 50,
+            min_requests: 20,
+            window_ms: 10_000,
+            open_ms: 30_000,
+            half_open_requests: 1,
+        }
+    }
+}
+
+//retries of failed idempotent requests on another node
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    pub max_retries: u32, //0 disables retries
+    pub per_try_timeout_ms: Option<u64>,
+    pub retry_on_status: Vec<u16>,
+    pub backoff_base_ms: u64,
+    pub backoff_max_ms: u64,
+    pub budget_percent: u32, //retries allowed as a share of requests in flight
+    pub min_concurrent_retries: usize,
+    pub idempotency_header: String, //marks other methods as safe to retry
+    pub max_replay_bytes: u64,      //larger bodies are streamed and never retried
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_retries: 2,
+            per_try_timeout_ms: None,
+            retry_on_status: vec![502, 503, 504],
+            backoff_base_ms: 25,
+            backoff_max_ms: 250,
+            budget_percent: 20,
+            min_concurrent_retries: 3,
+            idempotency_header: "idempotency-key".to_string(),
+            max_replay_bytes: 64 * 1024,
+        }
+    }
+}
+
+//timeouts for proxied traffic, routes override the global request and idle timeouts
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    pub connect_ms: u64,         //connecting to a node
+    pub request_ms: Option<u64>, //whole upstream exchange including the body
+    pub idle_ms: Option<u64>,    //longest gap between chunks of an upstream body
+    pub header_read_ms: u64,     //clients must send their request headers within this
+    pub drain_ms: u64,           //how long open connections get to finish on shutdown
+    pub routes: Vec<RouteTimeout>,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            connect_ms: 5_000,
+            request_ms: None,
+            //off so server-sent events and long polls stay open, set it per route
+            idle_ms: None,
+            header_read_ms: 30_000,
+            drain_ms: 30_000,
+            routes: vec![],
+        }
+    }
+}
+
+//the longest matching prefix wins, unset fields fall back to the global ones
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RouteTimeout {
+    pub path_prefix: String,
+    #[serde(default)]
+    pub request_ms: Option<u64>,
+    #[serde(default)]
+    pub idle_ms: Option<u64>,
+}
+
+impl TimeoutConfig {
+    //request and idle timeouts for a path
+    pub fn for_path(&self, path: &str) -> (Option<Duration>, Option<Duration>) {
+        let route = self
+            .routes
+            .iter()
+            .filter(|route| path.starts_with(&route.path_prefix))
+            .max_by_key(|route| route.path_prefix.len());
+        let request_ms = route.and_then(|r| r.request_ms).or(self.request_ms);
+        let idle_ms = route.and_then(|r| r.idle_ms).or(self.idle_ms);
+        (
+            request_ms.map(Duration::from_millis),
+            idle_ms.map(Duration::from_millis),
+        )
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StickySession {
+    pub cookie_name: String,
+    pub ttl_secs: u64,
+    #[serde(default)]
+    pub secret: Option<String>, //signing key, random per process when unset
+}
+
+//api endpoints probed by the api health check feature
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    pub apis: Vec<Api>,
+    pub check_interval_ms: u64, //milliseconds, older releases read both as seconds
+    pub timeout_ms: u64,
+    pub failure_threshold: u32, //retries before an api is reported as failed
+    pub from_agents: bool,      //read every node agent's /apis report instead of probing from here
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            apis: vec![],
+            check_interval_ms: 10_000,
+            timeout_ms: 1000,
+            failure_threshold: 3,
+            from_agents: false,
+        }
+    }
+}
+
+impl ApiConfig {
+    //every problem as (field path, message)
+    pub fn validate(&self) -> Vec<(String, String)> {
+        let mut errors = vec![];
+        let mut check = |ok: bool, path: String, message: &str| {
+            if !ok {
+                errors.push((path, message.to_string()));
+            }
+        };
+        check(
+            self.check_interval_ms > 0,
+            "check_interval_ms".to_string(),
+            "must be greater than 0",
+        );
+        check(
+            self.timeout_ms > 0,
+            "timeout_ms".to_string(),
+            "must be greater than 0",
+        );
+        for (i, api) in self.apis.iter().enumerate() {
+            check(
+                Url::parse(&api.url).is_ok(),
+                format!("apis[{}].url", i),
+                "must be a full url",
+            );
+            check(
+                matches!(api.method.as_str(), "GET" | "POST"),
+                format!("apis[{}].method", i),
+                "must be GET or POST",
+            );
+        }
+        errors
+    }
+}
+
+//warnings logged from what the balancer samples, and where node metrics go
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MonitoringConfig {
+    pub tui_url: Option<String>, //gets every health check round's metrics, off when unset
+    pub cpu_percent: f64,        //warn when a node's cpu goes above this
+    pub ram_percent: f64,        //warn when a node's memory use goes above this
+    pub max_download_mbps: f64,  //warn when a node receives more than this, near its link speed
+    pub max_upload_mbps: f64,
+    pub max_connections: usize, //warn above this many tcp connections on the balancer host
+    pub connections_interval_ms: u64,
+    //warned below their value in older releases, only read to reject them
+    #[serde(skip_serializing)]
+    pub min_download_mbps: Option<f64>,
+    #[serde(skip_serializing)]
+    pub min_upload_mbps: Option<f64>,
+}
+
+impl Default for MonitoringConfig {
+    fn default() -> Self {
+        MonitoringConfig {
+            tui_url: None,
+            cpu_percent: 90.0,
+            ram_percent: 90.0,
+            max_download_mbps: 1000.0,
+            max_upload_mbps: 1000.0,
+            max_connections: 100,
+            connections_interval_ms: 60_000,
+            min_download_mbps: None,
+            min_upload_mbps: None,
+        }
+    }
+}
+
+//lets agents add themselves to the node list through /_lb/nodes
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistrationConfig {
+    pub enabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>, //agents must send it as a bearer token, required when enabled
+    pub ttl_ms: u64, //registered nodes not heard from for this long are removed
 }
 
-impl Default for Protocol {
+impl Default for RegistrationConfig {
     fn default() -> Self {
-        Protocol::RobinRound
+        RegistrationConfig {
+            enabled: false,
+            token: None,
+            ttl_ms: 30000,
+        }
     }
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize, Default)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Api {
+    pub url: String,
+    pub method: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct LoadBalancerConfig {
     pub ip: String,
+    #[serde(default = "default_port")]
+    pub port: u16, //with ip, where traffic is taken when listen is empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub listen: Vec<String>, //addresses like 0.0.0.0:3000 or [::]:3000, replaces ip and port
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin_listen: Option<String>, //serves /_lb/, which is not served at all without it
+    #[serde(default)]
     pub protocol: Protocol,
+    #[serde(default)]
     pub features: Vec<Features>,
-    pub nodes: Vec<String>,
+    pub nodes: Vec<Node>,
+    #[serde(default)]
+    pub hash_key: HashKey, //only used by the consistent hash protocol
+    #[serde(default)]
+    pub sticky_session: Option<StickySession>, //pins clients to a node with a cookie
+    #[serde(default)]
+    pub max_request_bytes: Option<u64>, //none for unlimited
+    #[serde(default)]
+    pub max_response_bytes: Option<u64>, //none for unlimited
+    #[serde(default)]
+    pub trust_forwarded_headers: bool, //extend x-forwarded-* from a proxy in front of us
+    #[serde(default)]
+    pub health_check: HealthCheckConfig,
+    #[serde(default)]
+    pub outlier_detection: OutlierDetectionConfig,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_checks: Option<ApiConfig>, //used by the api health check feature
+    #[serde(default)]
+    pub monitoring: MonitoringConfig,
+    #[serde(default)]
+    pub registration: RegistrationConfig,
+}
+
+impl Default for LoadBalancerConfig {
+    fn default() -> Self {
+        LoadBalancerConfig {
+            ip: String::new(),
+            port: default_port(),
+            listen: vec![],
+            admin_listen: None,
+            protocol: Protocol::default(),
+            features: vec![],
+            nodes: vec![],
+            hash_key: HashKey::default(),
+            sticky_session: None,
+            max_request_bytes: None,
+            max_response_bytes: None,
+            trust_forwarded_headers: false,
+            health_check: HealthCheckConfig::default(),
+            outlier_detection: OutlierDetectionConfig::default(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            retry: RetryConfig::default(),
+            timeouts: TimeoutConfig::default(),
+            api_checks: None,
+            monitoring: MonitoringConfig::default(),
+            registration: RegistrationConfig::default(),
+        }
+    }
+}
+
+impl LoadBalancerConfig {
+    pub fn listen_addresses(&self) -> Vec<SocketAddr> {
+        listen_addresses(&self.ip, self.port, &self.listen)
+    }
+
+    pub fn admin_address(&self) -> Option<SocketAddr> {
+        self.admin_listen.as_ref()?.parse().ok()
+    }
+
+    //every problem as (field path, message), serde already checked the types
+    pub fn validate(&self) -> Vec<(String, String)> {
+        let mut errors = vec![];
+        let mut check = |ok: bool, path: String, message: &str| {
+            if !ok {
+                errors.push((path, message.to_string()));
+            }
+        };
+
+        check(
+            self.ip.parse::<IpAddr>().is_ok(),
+            "ip".to_string(),
+            "must be an ip address",
+        );
+        for (path, message) in validate_listen(self.port, &self.listen) {
+            check(false, path, &message);
+        }
+        if let Some(admin) = &self.admin_listen {
+            match admin.parse::<SocketAddr>() {
+                Ok(admin) => check(
+                    !self.listen_addresses().contains(&admin),
+                    "admin_listen".to_string(),
+                    "must not be one of the traffic addresses",
+                ),
+                Err(_) => check(
+                    false,
+                    "admin_listen".to_string(),
+                    "must be an address with a port, like 127.0.0.1:3002",
+                ),
+            }
+        }
+        check(
+            !self.nodes.is_empty() || self.registration.enabled,
+            "nodes".to_string(),
+            "at least one node is required unless registration is enabled",
+        );
+        for (i, node) in self.nodes.iter().enumerate() {
+            let duplicate = self.nodes[..i]
+                .iter()
+                .any(|other| other.address() == node.address());
+            check(
+                !duplicate,
+                format!("nodes[{}]", i),
+                "the same node is listed twice",
+            );
+            check(
+                node.weight > 0,
+                format!("nodes[{}].weight", i),
+                "must be at least 1",
+            );
+            check(
+                node.max_connections != Some(0),
+                format!("nodes[{}].max_connections", i),
+                "must be at least 1, leave it out for unlimited",
+            );
+        }
+        match &self.hash_key {
+            HashKey::Header(name) | HashKey::Cookie(name) => {
+                check(!name.is_empty(), "hash_key".to_string(), "needs a name")
+            }
+            HashKey::PathPrefix(segments) => check(
+                *segments > 0,
+                "hash_key".to_string(),
+                "needs at least one path segment",
+            ),
+            HashKey::ClientIp => {}
+        }
+        if let Some(sticky) = &self.sticky_session {
+            check(
+                !sticky.cookie_name.is_empty(),
+                "sticky_session.cookie_name".to_string(),
+                "must not be empty",
+            );
+            check(
+                sticky.ttl_secs > 0,
+                "sticky_session.ttl_secs".to_string(),
+                "must be greater than 0",
+            );
+        }
+
+        let health = &self.health_check;
+        check(
+            health.interval_ms > 0,
+            "health_check.interval_ms".to_string(),
+            "must be greater than 0",
+        );
+        check(
+            health.timeout_ms > 0,
+            "health_check.timeout_ms".to_string(),
+            "must be greater than 0",
+        );
+        check(
+            health.rise > 0,
+            "health_check.rise".to_string(),
+            "must be at least 1",
+        );
+        check(
+            health.fall > 0,
+            "health_check.fall".to_string(),
+            "must be at least 1",
+        );
+
+        let outlier = &self.outlier_detection;
+        check(
+            outlier.consecutive_failures > 0,
+            "outlier_detection.consecutive_failures".to_string(),
+            "must be at least 1",
+        );
+        check(
+            outlier.base_ejection_ms <= outlier.max_ejection_ms,
+            "outlier_detection.base_ejection_ms".to_string(),
+            "must not be above max_ejection_ms",
+        );
+        check(
+            outlier.max_ejection_percent <= 100,
+            "outlier_detection.max_ejection_percent".to_string(),
+            "must be a percentage",
+        );
+
+        let breaker = &self.circuit_breaker;
+        check(
+            (1..=100).contains(&breaker.failure_rate_percent),
+            "circuit_breaker.failure_rate_percent".to_string(),
+            "must be between 1 and 100",
+        );
+        check(
+            breaker.window_ms > 0,
+            "circuit_breaker.window_ms".to_string(),
+            "must be greater than 0",
+        );
+        check(
+            breaker.max_requests > 0,
+            "circuit_breaker.max_requests".to_string(),
+            "must be at least 1",
+        );
+        check(
+            breaker.half_open_requests > 0,
+            "circuit_breaker.half_open_requests".to_string(),
+            "must be at least 1",
+        );
+
+        let retry = &self.retry;
+        for (i, status) in retry.retry_on_status.iter().enumerate() {
+            check(
+                (100..=599).contains(status),
+                format!("retry.retry_on_status[{}]", i),
+                "must be an http status code",
+            );
+        }
+        check(
+            retry.backoff_base_ms <= retry.backoff_max_ms,
+            "retry.backoff_base_ms".to_string(),
+            "must not be above backoff_max_ms",
+        );
+        check(
+            retry.budget_percent <= 100,
+            "retry.budget_percent".to_string(),
+            "must be a percentage",
+        );
+        check(
+            retry.per_try_timeout_ms != Some(0),
+            "retry.per_try_timeout_ms".to_string(),
+            "must be greater than 0",
+        );
+
+        let timeouts = &self.timeouts;
+        check(
+            timeouts.connect_ms > 0,
+            "timeouts.connect_ms".to_string(),
+            "must be greater than 0",
+        );
+        check(
+            timeouts.header_read_ms > 0,
+            "timeouts.header_read_ms".to_string(),
+            "must be greater than 0",
+        );
+        check(
+            timeouts.request_ms != Some(0),
+            "timeouts.request_ms".to_string(),
+            "must be greater than 0",
+        );
+        check(
+            timeouts.idle_ms != Some(0),
+            "timeouts.idle_ms".to_string(),
+            "must be greater than 0",
+        );
+        for (i, route) in timeouts.routes.iter().enumerate() {
+            check(
+                route.path_prefix.starts_with('/'),
+                format!("timeouts.routes[{}].path_prefix", i),
+                "must start with /",
+            );
+            check(
+                route.request_ms != Some(0),
+                format!("timeouts.routes[{}].request_ms", i),
+                "must be greater than 0",
+            );
+            check(
+                route.idle_ms != Some(0),
+                format!("timeouts.routes[{}].idle_ms", i),
+                "must be greater than 0",
+            );
+        }
+
+        match &self.api_checks {
+            Some(api_checks) => {
+                for (path, message) in api_checks.validate() {
+                    check(false, format!("api_checks.{}", path), &message);
+                }
+            }
+            None => check(
+                !self.features.contains(&Features::ApiHealthCheck),
+                "features".to_string(),
+                "ApiHealthCheck needs an api_checks section",
+            ),
+        }
+
+        //agents register on the admin address, the traffic addresses only proxy
+        let registration = &self.registration;
+        check(
+            !registration.enabled || self.admin_listen.is_some(),
+            "registration".to_string(),
+            "needs admin_listen to take registrations on",
+        );
+        //anyone who reaches admin_listen could add, reweigh or remove nodes otherwise
+        check(
+            !registration.enabled || registration.token.as_ref().is_some_and(|t| !t.is_empty()),
+            "registration.token".to_string(),
+            "is required while registration is enabled",
+        );
+        check(
+            registration.ttl_ms > 0,
+            "registration.ttl_ms".to_string(),
+            "must be greater than 0",
+        );
+
+        let monitoring = &self.monitoring;
+        if let Some(url) = &monitoring.tui_url {
+            check(
+                Url::parse(url).is_ok(),
+                "monitoring.tui_url".to_string(),
+                "must be a full url",
+            );
+        }
+        for (field, percent) in [
+            ("cpu_percent", monitoring.cpu_percent),
+            ("ram_percent", monitoring.ram_percent),
+        ] {
+            check(
+                (0.0..=100.0).contains(&percent),
+                format!("monitoring.{}", field),
+                "must be a percentage",
+            );
+        }
+        for (field, mbps) in [
+            ("max_download_mbps", monitoring.max_download_mbps),
+            ("max_upload_mbps", monitoring.max_upload_mbps),
+        ] {
+            check(
+                mbps > 0.0,
+                format!("monitoring.{}", field),
+                "must be greater than 0",
+            );
+        }
+        check(
+            monitoring.min_download_mbps.is_none(),
+            "monitoring.min_download_mbps".to_string(),
+            "was removed, set max_download_mbps, which warns above its value instead of below",
+        );
+        check(
+            monitoring.min_upload_mbps.is_none(),
+            "monitoring.min_upload_mbps".to_string(),
+            "was removed, set max_upload_mbps, which warns above its value instead of below",
+        );
+        check(
+            monitoring.connections_interval_ms > 0,
+            "monitoring.connections_interval_ms".to_string(),
+            "must be greater than 0",
+        );
+        errors
+    }
 }
 
-impl ToString for Features {
-    fn to_string(&self) -> String {
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let string = match self {
             Features::HealthCheck => "Health Check",
             Features::ApiHealthCheck => "Api Health Check",
         };
-        string.into()
+        f.write_str(string)
     }
 }
 
-impl ToString for Protocol {
-    fn to_string(&self) -> String {
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let string = match self {
             Protocol::RobinRound => "Robin Round",
             Protocol::LeastConnections => "Least Connections",
-            Protocol::LeastResponse => "Leas tResponse",
+            Protocol::LeastResponse => "Least Response",
+            Protocol::ResourceAware => "Resource Aware",
+            Protocol::WeightedRobinRound => "Weighted Robin Round",
+            Protocol::ConsistentHash => "Consistent Hash",
         };
-        string.into()
+        f.write_str(string)
+    }
+}
+
+//"robin-round", "RobinRound" and "Robin Round" all name the same thing
+fn normalize(input: &str) -> String {
+    input
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '-' | '_'))
+        .collect::<String>()
+        .to_lowercase()
+}
+
+impl FromStr for Features {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match normalize(input).as_str() {
+            "healthcheck" => Ok(Features::HealthCheck),
+            "apihealthcheck" => Ok(Features::ApiHealthCheck),
+            _ => Err(format!("unknown feature {}", input)),
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match normalize(input).as_str() {
+            "robinround" => Ok(Protocol::RobinRound),
+            "leastconnections" => Ok(Protocol::LeastConnections),
+            "leastresponse" => Ok(Protocol::LeastResponse),
+            "resourceaware" => Ok(Protocol::ResourceAware),
+            "weightedrobinround" => Ok(Protocol::WeightedRobinRound),
+            "consistenthash" => Ok(Protocol::ConsistentHash),
+            _ => Err(format!("unknown protocol {}", input)),
+        }
+    }
+}
+
+//client-ip, header:<name>, cookie:<name> or path-prefix:<segments>
+impl FromStr for HashKey {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = input.split_once(':').unwrap_or((input, ""));
+        let value = value.trim();
+        match (normalize(kind).as_str(), value.is_empty()) {
+            ("clientip", true) => Ok(HashKey::ClientIp),
+            ("header", false) => Ok(HashKey::Header(value.to_string())),
+            ("cookie", false) => Ok(HashKey::Cookie(value.to_string())),
+            ("pathprefix", false) => value
+                .parse()
+                .map(HashKey::PathPrefix)
+                .map_err(|_| format!("invalid path segment count {}", value)),
+            _ => Err(format!("invalid hash key {}", input)),
+        }
+    }
+}
+
+//a node url followed by optional settings, for example
+//http://10.0.0.1:8080,weight=2,agent_port=4001,max_connections=100,draining
+impl FromStr for Node {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let mut parts = input.split(',');
+        let mut node = Node::try_from(NodeEntry::Address(
+            parts.next().unwrap_or_default().to_string(),
+        ))?;
+        for part in parts {
+            let (key, value) = part.split_once('=').unwrap_or((part, ""));
+            let invalid = || format!("invalid value {} for {}", value, key);
+            match normalize(key).as_str() {
+                "agentport" => node.agent_port = value.parse().map_err(|_| invalid())?,
+                "weight" => node.weight = value.parse().map_err(|_| invalid())?,
+                "maxconnections" => {
+                    node.max_connections = Some(value.parse().map_err(|_| invalid())?)
+                }
+                "draining" => node.draining = true,
+                _ => return Err(format!("unknown node setting {}", key)),
+            }
+        }
+        Ok(node)
+    }
+}
+
+//the config saved by the wizard when no config file is given
+pub fn load_config() -> Result<LoadBalancerConfig, ConfyError> {
+    confy::load("load-balancer-config", None)
+}
+
+//writes the load_balancer section of a config file, or the saved config
+pub fn store_config(path: Option<&Path>, config: &LoadBalancerConfig) -> Result<(), String> {
+    match path {
+        Some(path) => cluster_config::update(path, "load_balancer", config),
+        None => confy::store("load-balancer-config", None, config).map_err(|e| format!("{:?}", e)),
     }
 }
 
 pub async fn configure_load_balancer(
     protocols: &[Protocol],
     features: &[Features],
-) -> Result<(), ConfyError> {
+    path: Option<&Path>,
+) -> Result<LoadBalancerConfig, String> {
     let protocol = Select::with_theme(&ColorfulTheme::default())
         .with_prompt("Select Protocol")
         .default(0)
-        .items(&protocols)
+        .items(protocols)
         .interact()
         .unwrap();
     let protocol = &protocols[protocol];
 
+    let hash_key = match protocol {
+        Protocol::ConsistentHash => configure_hash_key(),
+        _ => HashKey::default(),
+    };
+
     let defaults = &[true, false];
     let features_selected = MultiSelect::with_theme(&ColorfulTheme::default())
         .with_prompt("Select Features")
-        .items(&features)
+        .items(features)
         .defaults(&defaults[..])
         .interact()
         .unwrap();
@@ -75,51 +1005,77 @@ pub async fn configure_load_balancer(
         .map(|&index| features[index].clone())
         .collect();
 
+    let api_checks = selected_features
+        .contains(&Features::ApiHealthCheck)
+        .then(configure_api_checks);
+
+    let sticky = Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt("Enable sticky sessions?")
+        .default(false)
+        .interact()
+        .unwrap();
+    let sticky_session = sticky.then(configure_sticky_session);
+
     let ip: String = Input::with_theme(&ColorfulTheme::default())
-        .with_prompt("Load Balancer IPv4")
+        .with_prompt("Load Balancer IP (v4 or v6)")
         .validate_with(|input: &String| -> Result<(), &str> {
-            let numbers: Vec<&str> = input.split('.').collect();
-
-            if numbers.len() != 4 {
-                return Err("IPv4 address must have exactly 4 octets");
+            match input.parse::<IpAddr>() {
+                Ok(_) => Ok(()),
+                Err(_) => Err("Not an IPv4 or IPv6 address"),
             }
-
-            for num in numbers {
-                match num.parse::<u8>() {
-                    Ok(_) => continue,
-                    Err(_) => return Err("Each octet must be a number between 0 and 255"),
-                }
+        })
+        .interact_text()
+        .unwrap();
+    let port: u16 = Input::with_theme(&ColorfulTheme::default())
+        .with_prompt("Load Balancer port")
+        .default(default_port())
+        .validate_with(|input: &u16| -> Result<(), &str> {
+            if *input == 0 {
+                return Err("Port must be at least 1");
             }
-
             Ok(())
         })
         .interact_text()
         .unwrap();
 
     let mut next_ip = true;
-    let mut nodes: Vec<String> = vec![];
+    let mut nodes: Vec<Node> = vec![];
     while next_ip {
-        let node_ip: String = Input::with_theme(&ColorfulTheme::default())
-            .with_prompt("Connected Server IPv4")
-            .validate_with(|input: &String| -> Result<(), &str> {
-                let numbers: Vec<&str> = input.split('.').collect();
-
-                if numbers.len() != 4 {
-                    return Err("IPv4 address must have exactly 4 octets");
-                }
-
-                for num in numbers {
-                    match num.parse::<u8>() {
-                        Ok(_) => continue,
-                        Err(_) => return Err("Each octet must be a number between 0 and 255"),
-                    }
+        let node_address: String = Input::with_theme(&ColorfulTheme::default())
+            .with_prompt("Connected Server (url or host:port)")
+            .validate_with(|input: &String| -> Result<(), String> {
+                parse_node_url(input).map(|_| ())
+            })
+            .interact_text()
+            .unwrap();
+        let agent_port: u16 = Input::with_theme(&ColorfulTheme::default())
+            .with_prompt("Node agent port")
+            .default(default_agent_port())
+            .interact_text()
+            .unwrap();
+        let weight: u32 = Input::with_theme(&ColorfulTheme::default())
+            .with_prompt("Node weight")
+            .default(1)
+            .validate_with(|input: &u32| -> Result<(), &str> {
+                if *input == 0 {
+                    return Err("Weight must be at least 1");
                 }
-
                 Ok(())
             })
             .interact_text()
             .unwrap();
-        nodes.push(node_ip);
+        let max_connections: usize = Input::with_theme(&ColorfulTheme::default())
+            .with_prompt("Max connections (0 for unlimited)")
+            .default(0)
+            .interact_text()
+            .unwrap();
+        nodes.push(Node {
+            url: parse_node_url(&node_address).expect("address was validated"),
+            agent_port,
+            weight,
+            max_connections: (max_connections > 0).then_some(max_connections),
+            draining: false,
+        });
 
         let add_more = Confirm::with_theme(&ColorfulTheme::default())
             .with_prompt("Add more nodes?")
@@ -132,9 +1088,25 @@ pub async fn configure_load_balancer(
     }
     let config = LoadBalancerConfig {
         ip: ip.clone(),
+        port,
+        listen: vec![],
+        admin_listen: None,
         protocol: protocol.clone(),
         features: selected_features.clone(),
         nodes: nodes.clone(),
+        hash_key,
+        sticky_session,
+        max_request_bytes: None,
+        max_response_bytes: None,
+        trust_forwarded_headers: false,
+        health_check: HealthCheckConfig::default(),
+        outlier_detection: OutlierDetectionConfig::default(),
+        circuit_breaker: CircuitBreakerConfig::default(),
+        retry: RetryConfig::default(),
+        timeouts: TimeoutConfig::default(),
+        api_checks,
+        monitoring: MonitoringConfig::default(),
+        registration: RegistrationConfig::default(),
     };
     info!("Load Balancer Config: {:#?}", config);
 
@@ -142,5 +1114,285 @@ pub async fn configure_load_balancer(
         error!("Invalid configuration. Please check the IP addresses and try again.");
         std::process::exit(1);
     }
-    confy::store("load-balancer-config", None, config)
+    store_config(path, &config)?;
+    Ok(config)
+}
+
+fn configure_hash_key() -> HashKey {
+    let kinds = ["Client IP", "Header", "Cookie", "Path Prefix"];
+    let kind = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt("Hash requests by")
+        .default(0)
+        .items(&kinds)
+        .interact()
+        .unwrap();
+    match kind {
+        1 => HashKey::Header(
+            Input::with_theme(&ColorfulTheme::default())
+                .with_prompt("Header name")
+                .interact_text()
+                .unwrap(),
+        ),
+        2 => HashKey::Cookie(
+            Input::with_theme(&ColorfulTheme::default())
+                .with_prompt("Cookie name")
+                .interact_text()
+                .unwrap(),
+        ),
+        3 => HashKey::PathPrefix(
+            Input::with_theme(&ColorfulTheme::default())
+                .with_prompt("Number of path segments")
+                .default(1)
+                .interact_text()
+                .unwrap(),
+        ),
+        _ => HashKey::ClientIp,
+    }
+}
+
+pub fn configure_api_checks() -> ApiConfig {
+    let methods = ["GET", "POST"];
+    let mut apis = vec![];
+    loop {
+        let url: String = Input::with_theme(&ColorfulTheme::default())
+            .with_prompt("Api url to check")
+            .validate_with(|input: &String| -> Result<(), String> {
+                Url::parse(input).map(|_| ()).map_err(|e| e.to_string())
+            })
+            .interact_text()
+            .unwrap();
+        let method = Select::with_theme(&ColorfulTheme::default())
+            .with_prompt("Method")
+            .default(0)
+            .items(&methods)
+            .interact()
+            .unwrap();
+        apis.push(Api {
+            url,
+            method: methods[method].to_string(),
+            body: None,
+        });
+
+        let add_more = Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt("Add more apis?")
+            .default(false)
+            .interact()
+            .unwrap();
+        if !add_more {
+            break;
+        }
+    }
+    ApiConfig {
+        apis,
+        ..ApiConfig::default()
+    }
+}
+
+fn configure_sticky_session() -> StickySession {
+    let cookie_name: String = Input::with_theme(&ColorfulTheme::default())
+        .with_prompt("Affinity cookie name")
+        .default("lb_affinity".to_string())
+        .interact_text()
+        .unwrap();
+    let ttl_secs: u64 = Input::with_theme(&ColorfulTheme::default())
+        .with_prompt("Affinity cookie TTL (seconds)")
+        .default(3600)
+        .interact_text()
+        .unwrap();
+    StickySession {
+        cookie_name,
+        ttl_secs,
+        secret: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(json: &str) -> Result<Node, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    #[test]
+    fn bare_ip_defaults_to_http_and_agent_port() {
+        let node = node(r#""10.0.0.1""#).unwrap();
+        assert_eq!(node.upstream_url("/a?b=c"), "http://10.0.0.1/a?b=c");
+        assert_eq!(node.agent_url("/metrics"), "http://10.0.0.1:3001/metrics");
+        assert_eq!(node.weight, 1);
+    }
+
+    #[test]
+    fn bare_ip_node_lists_still_load() {
+        let cfg: LoadBalancerConfig =
+            serde_yaml::from_str("ip: 10.0.0.9\nnodes: [10.0.0.1, '10.0.0.2:8080']").unwrap();
+        let addresses: Vec<String> = cfg.nodes.iter().map(Node::address).collect();
+        assert_eq!(addresses, vec!["10.0.0.1:80", "10.0.0.2:8080"]);
+        assert!(cfg.validate().is_empty(), "{:?}", cfg.validate());
+    }
+
+    #[test]
+    fn old_bandwidth_floors_are_rejected() {
+        let cfg: LoadBalancerConfig = serde_yaml::from_str(
+            "ip: 10.0.0.9\nnodes: [10.0.0.1]\nmonitoring: {min_download_mbps: 50}",
+        )
+        .unwrap();
+        assert_eq!(cfg.monitoring.max_download_mbps, 1000.0);
+        let errors = cfg.validate();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, "monitoring.min_download_mbps");
+        assert!(errors[0].1.contains("max_download_mbps"));
+        assert!(!serde_yaml::to_string(&cfg.monitoring)
+            .unwrap()
+            .contains("min_"));
+    }
+
+    #[test]
+    fn zero_weight_is_an_error() {
+        let cfg = LoadBalancerConfig {
+            ip: "10.0.0.9".to_string(),
+            nodes: vec!["10.0.0.1,weight=0".parse().unwrap()],
+            ..LoadBalancerConfig::default()
+        };
+        assert_eq!(
+            cfg.validate(),
+            vec![(
+                "nodes[0].weight".to_string(),
+                "must be at least 1".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn registration_needs_admin_listen_and_a_token() {
+        let mut cfg = LoadBalancerConfig {
+            ip: "10.0.0.9".to_string(),
+            registration: RegistrationConfig {
+                enabled: true,
+                token: Some("secret".to_string()),
+                ..RegistrationConfig::default()
+            },
+            ..LoadBalancerConfig::default()
+        };
+        assert_eq!(
+            cfg.validate(),
+            vec![(
+                "registration".to_string(),
+                "needs admin_listen to take registrations on".to_string()
+            )]
+        );
+        cfg.admin_listen = Some("127.0.0.1:3002".to_string());
+        assert!(cfg.validate().is_empty());
+        for token in [None, Some(String::new())] {
+            cfg.registration.token = token;
+            assert_eq!(cfg.validate()[0].0, "registration.token");
+        }
+    }
+
+    #[test]
+    fn legacy_ip_table_still_loads() {
+        let node = node(r#"{"ip": "10.0.0.1", "weight": 3}"#).unwrap();
+        assert_eq!(node.address(), "10.0.0.1:80");
+        assert_eq!(node.weight, 3);
+    }
+
+    #[test]
+    fn full_definition_keeps_scheme_and_ports() {
+        let node = node(r#"{"url": "https://api.internal:8443", "agent_port": 4001}"#).unwrap();
+        assert_eq!(node.upstream_url("/"), "https://api.internal:8443/");
+        assert_eq!(
+            node.agent_url("/metrics"),
+            "http://api.internal:4001/metrics"
+        );
+    }
+
+    #[test]
+    fn ipv6_hosts_keep_brackets() {
+        let node = node(r#""[::1]:8080""#).unwrap();
+        assert_eq!(node.address(), "[::1]:8080");
+    }
+
+    #[test]
+    fn node_flags_carry_settings() {
+        let node: Node = "10.0.0.1:8080,weight=3,agent_port=4001,max_connections=50,draining"
+            .parse()
+            .unwrap();
+        assert_eq!(node.address(), "10.0.0.1:8080");
+        assert_eq!(node.agent_port, 4001);
+        assert_eq!(node.weight, 3);
+        assert_eq!(node.max_connections, Some(50));
+        assert!(node.draining);
+        assert!("10.0.0.1,weight=heavy".parse::<Node>().is_err());
+        assert!("10.0.0.1,color=red".parse::<Node>().is_err());
+    }
+
+    #[test]
+    fn names_parse_in_any_spelling() {
+        assert!(matches!(
+            "weighted-robin-round".parse(),
+            Ok(Protocol::WeightedRobinRound)
+        ));
+        assert!(matches!(
+            "Least Response".parse(),
+            Ok(Protocol::LeastResponse)
+        ));
+        assert_eq!("api_health_check".parse(), Ok(Features::ApiHealthCheck));
+        assert_eq!(
+            "header:X-User".parse(),
+            Ok(HashKey::Header("X-User".to_string()))
+        );
+        assert_eq!("path-prefix:2".parse(), Ok(HashKey::PathPrefix(2)));
+        assert!("header".parse::<HashKey>().is_err());
+    }
+
+    #[test]
+    fn invalid_addresses_are_rejected() {
+        assert!(node(r#""ftp://10.0.0.1""#).is_err());
+        assert!(node(r#""10.0.0.1:notaport""#).is_err());
+    }
+
+    #[test]
+    fn listen_defaults_to_ip_and_port() {
+        let mut cfg = LoadBalancerConfig {
+            ip: "::1".to_string(),
+            ..LoadBalancerConfig::default()
+        };
+        assert_eq!(cfg.listen_addresses(), vec!["[::1]:3000".parse().unwrap()]);
+        cfg.listen = vec!["0.0.0.0:80".to_string(), "[::]:8080".to_string()];
+        assert_eq!(
+            cfg.listen_addresses(),
+            vec![
+                "0.0.0.0:80".parse::<SocketAddr>().unwrap(),
+                "[::]:8080".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn route_timeouts_override_global_ones() {
+        let timeouts = TimeoutConfig {
+            request_ms: Some(1000),
+            idle_ms: Some(500),
+            routes: vec![
+                RouteTimeout {
+                    path_prefix: "/reports".to_string(),
+                    request_ms: Some(30_000),
+                    idle_ms: None,
+                },
+                RouteTimeout {
+                    path_prefix: "/reports/export".to_string(),
+                    request_ms: Some(120_000),
+                    idle_ms: None,
+                },
+            ],
+            ..TimeoutConfig::default()
+        };
+        let ms = |d: Option<Duration>| d.map(|d| d.as_millis());
+        let (request, idle) = timeouts.for_path("/reports/export/1");
+        assert_eq!((ms(request), ms(idle)), (Some(120_000), Some(500)));
+        let (request, _) = timeouts.for_path("/reports/daily");
+        assert_eq!(ms(request), Some(30_000));
+        let (request, _) = timeouts.for_path("/users");
+        assert_eq!(ms(request), Some(1000));
+    }
 }
@@ -0,0 +1,170 @@
+//maps config paths like `load_balancer.nodes[1].weight` to the line they are
+//written on, so errors found after parsing can still point into the file
+use std::collections::HashMap;
+use toml_edit::{ImDocument, Item, Table, Value};
+use yaml_rust2::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust2::scanner::Marker;
+
+pub type Lines = HashMap<String, usize>;
+
+//the line of `path`, or of its closest ancestor written in the file
+pub fn line_of(lines: &Lines, path: &str) -> Option<usize> {
+    let mut path = path;
+    loop {
+        if let Some(line) = lines.get(path) {
+            return Some(*line);
+        }
+        let parent = path.rfind(['.', '['])?;
+        path = &path[..parent];
+    }
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+enum Frame {
+    Map(Option<String>), //the key waiting for its value
+    Seq(usize),          //index of the next item
+}
+
+#[derive(Default)]
+struct YamlLines {
+    frames: Vec<Frame>,
+    lines: Lines,
+}
+
+impl YamlLines {
+    fn path(&self) -> String {
+        let mut path = String::new();
+        for frame in &self.frames {
+            match frame {
+                Frame::Map(Some(key)) => path = join(&path, key),
+                Frame::Map(None) => {}
+                Frame::Seq(index) => path = format!("{}[{}]", path, index),
+            }
+        }
+        path
+    }
+
+    fn enter(&mut self, mark: Marker) {
+        let path = self.path();
+        self.lines.entry(path).or_insert(mark.line());
+    }
+
+    fn leave(&mut self) {
+        match self.frames.last_mut() {
+            Some(Frame::Map(key)) => *key = None,
+            Some(Frame::Seq(index)) => *index += 1,
+            None => {}
+        }
+    }
+}
+
+impl MarkedEventReceiver for YamlLines {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        match event {
+            Event::Scalar(value, ..) => {
+                if let Some(Frame::Map(key @ None)) = self.frames.last_mut() {
+                    *key = Some(value);
+                    self.enter(mark);
+                } else {
+                    self.enter(mark);
+                    self.leave();
+                }
+            }
+            Event::Alias(_) => {
+                self.enter(mark);
+                self.leave();
+            }
+            Event::MappingStart(..) => {
+                self.enter(mark);
+                self.frames.push(Frame::Map(None));
+            }
+            Event::SequenceStart(..) => {
+                self.enter(mark);
+                self.frames.push(Frame::Seq(0));
+            }
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.frames.pop();
+                self.leave();
+            }
+            _ => {}
+        }
+    }
+}
+
+//empty when the source does not parse, the parse error is reported instead
+pub fn yaml_lines(source: &str) -> Lines {
+    let mut receiver = YamlLines::default();
+    let _ = Parser::new_from_str(source).load(&mut receiver, false);
+    receiver.lines
+}
+
+pub fn toml_lines(source: &str) -> Lines {
+    let mut lines = Lines::new();
+    if let Ok(document) = ImDocument::parse(source) {
+        let line = |offset: usize| source[..offset].matches('\n').count() + 1;
+        toml_table(&document, "", &line, &mut lines);
+    }
+    lines
+}
+
+fn toml_table(table: &Table, path: &str, line: &dyn Fn(usize) -> usize, lines: &mut Lines) {
+    for (key, item) in table.iter() {
+        let path = join(path, key);
+        let span = table
+            .key(key)
+            .and_then(|key| key.span())
+            .or_else(|| item.span());
+        if let Some(span) = span {
+            lines.insert(path.clone(), line(span.start));
+        }
+        match item {
+            Item::Table(table) => toml_table(table, &path, line, lines),
+            Item::ArrayOfTables(tables) => {
+                for (index, table) in tables.iter().enumerate() {
+                    let path = format!("{}[{}]", path, index);
+                    if let Some(span) = table.span() {
+                        lines.insert(path.clone(), line(span.start));
+                    }
+                    toml_table(table, &path, line, lines);
+                }
+            }
+            Item::Value(value) => toml_value(value, &path, line, lines),
+            Item::None => {}
+        }
+    }
+}
+
+fn toml_value(value: &Value, path: &str, line: &dyn Fn(usize) -> usize, lines: &mut Lines) {
+    match value {
+        Value::Array(array) => {
+            for (index, value) in array.iter().enumerate() {
+                let path = format!("{}[{}]", path, index);
+                if let Some(span) = value.span() {
+                    lines.insert(path.clone(), line(span.start));
+                }
+                toml_value(value, &path, line, lines);
+            }
+        }
+        Value::InlineTable(table) => {
+            for (key, value) in table.iter() {
+                let path = join(path, key);
+                let span = table
+                    .key(key)
+                    .and_then(|key| key.span())
+                    .or_else(|| value.span());
+                if let Some(span) = span {
+                    lines.insert(path.clone(), line(span.start));
+                }
+                toml_value(value, &path, line, lines);
+            }
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,359 @@
+//a host running small services, each behind a local reverse proxy that
+//registers it with its balancers, next to an embedded node agent
+use crate::config::cluster_config;
+use crate::config::server_config::DEFAULT_AGENT_PORT;
+use confy::ConfyError;
+use dialoguer::{theme::ColorfulTheme, Confirm, Input};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+fn default_agent_port() -> u16 {
+    DEFAULT_AGENT_PORT
+}
+
+fn default_health_interval_ms() -> u64 {
+    5000
+}
+
+fn default_metrics_interval_ms() -> u64 {
+    1000
+}
+
+fn default_health_path() -> String {
+    "/health".to_string()
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MicroServerConfig {
+    pub ip: String, //address balancers reach this host on
+    #[serde(default = "default_agent_port")]
+    pub agent_port: u16, //embedded agent serving the same /metrics as a node
+    #[serde(default = "default_health_interval_ms")]
+    pub health_interval_ms: u64, //how often every service's health path is checked
+    #[serde(default = "default_metrics_interval_ms")]
+    pub metrics_interval_ms: u64, //how often the agent's /metrics is sampled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>, //the registration.token of the balancers, they require one
+    #[serde(default)]
+    pub trust_forwarded_headers: bool, //keep x-forwarded-* when only balancers reach the proxies
+    pub services: Vec<ServiceConfig>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceConfig {
+    pub name: String,
+    pub port: u16,       //where the service listens on this host's loopback
+    pub proxy_port: u16, //where balancers send the service's traffic
+    #[serde(default = "default_health_path")]
+    pub health_path: String, //answers 2xx while the service can take requests
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    pub loadbalancers: Vec<String>, //admin addresses to register with, like 10.0.0.1:3002
+}
+
+impl Default for MicroServerConfig {
+    fn default() -> Self {
+        MicroServerConfig {
+            ip: String::new(),
+            agent_port: default_agent_port(),
+            health_interval_ms: default_health_interval_ms(),
+            metrics_interval_ms: default_metrics_interval_ms(),
+            token: None,
+            trust_forwarded_headers: false,
+            services: vec![],
+        }
+    }
+}
+
+impl MicroServerConfig {
+    //where balancers send a service's traffic, call after validating
+    pub fn proxy_address(&self, service: &ServiceConfig) -> Option<SocketAddr> {
+        let ip: IpAddr = self.ip.parse().ok()?;
+        Some(SocketAddr::new(ip, service.proxy_port))
+    }
+
+    //every problem as (field path, message)
+    pub fn validate(&self) -> Vec<(String, String)> {
+        let mut errors = vec![];
+        let mut check = |ok: bool, path: String, message: &str| {
+            if !ok {
+                errors.push((path, message.to_string()));
+            }
+        };
+
+        check(
+            self.ip.parse::<IpAddr>().is_ok(),
+            "ip".to_string(),
+            "must be an ip address",
+        );
+        check(
+            self.agent_port > 0,
+            "agent_port".to_string(),
+            "must be greater than 0",
+        );
+        check(
+            self.health_interval_ms > 0,
+            "health_interval_ms".to_string(),
+            "must be greater than 0",
+        );
+        check(
+            self.metrics_interval_ms > 0,
+            "metrics_interval_ms".to_string(),
+            "must be greater than 0",
+        );
+        check(
+            !self.services.is_empty(),
+            "services".to_string(),
+            "at least one service is required",
+        );
+        for (i, service) in self.services.iter().enumerate() {
+            let path = |field: &str| format!("services[{}].{}", i, field);
+            let earlier = &self.services[..i];
+            check(!service.name.is_empty(), path("name"), "must not be empty");
+            check(
+                !earlier.iter().any(|other| other.name == service.name),
+                path("name"),
+                "the same service is listed twice",
+            );
+            check(service.port > 0, path("port"), "must be greater than 0");
+            check(
+                service.proxy_port > 0,
+                path("proxy_port"),
+                "must be greater than 0",
+            );
+            check(
+                service.proxy_port != self.agent_port
+                    && !earlier
+                        .iter()
+                        .any(|other| other.proxy_port == service.proxy_port),
+                path("proxy_port"),
+                "is already taken by the agent or another service",
+            );
+            check(
+                service.health_path.starts_with('/'),
+                path("health_path"),
+                "must start with /",
+            );
+            check(service.weight > 0, path("weight"), "must be at least 1");
+            check(
+                !service.loadbalancers.is_empty(),
+                path("loadbalancers"),
+                "at least one load balancer is required",
+            );
+            for (j, address) in service.loadbalancers.iter().enumerate() {
+                check(
+                    address.parse::<SocketAddr>().is_ok(),
+                    format!("services[{}].loadbalancers[{}]", i, j),
+                    "must be an admin address with a port, like 10.0.0.1:3002",
+                );
+            }
+        }
+        errors
+    }
+}
+
+//the config saved by the wizard when no config file is given
+pub fn load_config() -> Result<MicroServerConfig, ConfyError> {
+    confy::load("micro-server-config", None)
+}
+
+//writes the micro_server section of a config file, or the saved config
+pub fn store_config(path: Option<&Path>, config: &MicroServerConfig) -> Result<(), String> {
+    match path {
+        Some(path) => cluster_config::update(path, "micro_server", config),
+        None => confy::store("micro-server-config", None, config).map_err(|e| format!("{:?}", e)),
+    }
+}
+
+fn configure_service(agent_port: u16) -> ServiceConfig {
+    let theme = ColorfulTheme::default();
+    let name: String = Input::with_theme(&theme)
+        .with_prompt("Service name")
+        .interact_text()
+        .unwrap();
+    let port: u16 = Input::with_theme(&theme)
+        .with_prompt("Port the service listens on locally")
+        .interact_text()
+        .unwrap();
+    let proxy_port: u16 = Input::with_theme(&theme)
+        .with_prompt("Port balancers send its traffic to")
+        .validate_with(|input: &u16| -> Result<(), &str> {
+            if *input == 0 || *input == agent_port {
+                return Err("Must be a free port other than the agent's");
+            }
+            Ok(())
+        })
+        .interact_text()
+        .unwrap();
+    let health_path: String = Input::with_theme(&theme)
+        .with_prompt("Health path")
+        .default(default_health_path())
+        .interact_text()
+        .unwrap();
+    let weight: u32 = Input::with_theme(&theme)
+        .with_prompt("Weight")
+        .default(default_weight())
+        .interact_text()
+        .unwrap();
+
+    let mut loadbalancers = vec![];
+    loop {
+        let address: String = Input::with_theme(&theme)
+            .with_prompt("Load balancer admin address (ip:port)")
+            .validate_with(|input: &String| -> Result<(), &str> {
+                match input.parse::<SocketAddr>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err("Must be an ip and port, like 10.0.0.1:3002"),
+                }
+            })
+            .interact_text()
+            .unwrap();
+        loadbalancers.push(address);
+        let add_more = Confirm::with_theme(&theme)
+            .with_prompt("Register with another load balancer?")
+            .default(false)
+            .interact()
+            .unwrap();
+        if !add_more {
+            break;
+        }
+    }
+
+    ServiceConfig {
+        name,
+        port,
+        proxy_port,
+        health_path,
+        weight,
+        loadbalancers,
+    }
+}
+
+//none when the entered config did not validate
+pub fn configure_micro_server(path: Option<&Path>) -> Option<MicroServerConfig> {
+    let theme = ColorfulTheme::default();
+    let ip: String = Input::with_theme(&theme)
+        .with_prompt("IP address balancers reach this host on")
+        .validate_with(|input: &String| -> Result<(), &str> {
+            match input.parse::<IpAddr>() {
+                Ok(_) => Ok(()),
+                Err(_) => Err("Not an IPv4 or IPv6 address"),
+            }
+        })
+        .interact_text()
+        .unwrap();
+    let agent_port: u16 = Input::with_theme(&theme)
+        .with_prompt("Agent port")
+        .default(default_agent_port())
+        .interact_text()
+        .unwrap();
+
+    let mut services = vec![];
+    loop {
+        services.push(configure_service(agent_port));
+        let add_more = Confirm::with_theme(&theme)
+            .with_prompt("Add another service?")
+            .default(false)
+            .interact()
+            .unwrap();
+        if !add_more {
+            break;
+        }
+    }
+
+    let config = MicroServerConfig {
+        ip,
+        agent_port,
+        services,
+        ..MicroServerConfig::default()
+    };
+    println!("Micro Server Config: {:#?}", config);
+
+    let errors = config.validate();
+    if !errors.is_empty() {
+        for (path, message) in errors {
+            println!("❌ {}: {}", path, message);
+        }
+        return None;
+    }
+    store_config(path, &config).expect("Failed to store config");
+    Some(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(name: &str, proxy_port: u16) -> ServiceConfig {
+        ServiceConfig {
+            name: name.to_string(),
+            port: 8080,
+            proxy_port,
+            health_path: default_health_path(),
+            weight: 1,
+            loadbalancers: vec!["10.0.0.1:3002".to_string()],
+        }
+    }
+
+    fn config(services: Vec<ServiceConfig>) -> MicroServerConfig {
+        MicroServerConfig {
+            ip: "10.0.0.5".to_string(),
+            services,
+            ..MicroServerConfig::default()
+        }
+    }
+
+    #[test]
+    fn several_services_share_a_host() {
+        let config = config(vec![service("orders", 8081), service("users", 8082)]);
+        assert!(config.validate().is_empty());
+        assert_eq!(
+            config.proxy_address(&config.services[1]),
+            Some("10.0.0.5:8082".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn services_need_their_own_names_and_ports() {
+        let mut users = service("orders", 8081);
+        users.health_path = "health".to_string();
+        users.loadbalancers = vec!["10.0.0.1".to_string()];
+        let errors: Vec<String> = config(vec![service("orders", 8081), users])
+            .validate()
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "services[1].name",
+                "services[1].proxy_port",
+                "services[1].health_path",
+                "services[1].loadbalancers[0]",
+            ]
+        );
+    }
+
+    #[test]
+    fn proxy_ports_avoid_the_agent() {
+        let errors = config(vec![service("orders", DEFAULT_AGENT_PORT)]).validate();
+        assert_eq!(errors[0].0, "services[0].proxy_port");
+    }
+
+    #[test]
+    fn defaults_fill_in_optional_fields() {
+        let config: MicroServerConfig = serde_yaml::from_str(
+            "ip: 10.0.0.5\nservices:\n  - {name: orders, port: 8080, proxy_port: 8081, loadbalancers: ['10.0.0.1:3002']}\n",
+        )
+        .unwrap();
+        assert_eq!(config.agent_port, DEFAULT_AGENT_PORT);
+        assert_eq!(config.services[0].health_path, "/health");
+        assert_eq!(config.services[0].weight, 1);
+    }
+}
@@ -1,2 +1,6 @@
+pub mod cluster_config;
+pub mod env;
 pub mod loadbalancer_config;
+mod locate;
+pub mod microserver_config;
 pub mod server_config;
@@ -1,34 +1,228 @@
-use crate::subapps::node::server_listener;
+use crate::config::cluster_config::{self, listen_addresses, validate_listen};
+use crate::config::loadbalancer_config::{configure_api_checks, ApiConfig, Node, DEFAULT_PORT};
 use crate::validator::validate::validate_server_config;
+use confy::ConfyError;
 use dialoguer::{theme::ColorfulTheme, Confirm, Input};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
 pub enum ServerListener {
     HealthCheckListener,
     ApiHealthCheckListener,
 }
-impl ToString for ServerListener {
-    fn to_string(&self) -> String {
+impl fmt::Display for ServerListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let string = match self {
             ServerListener::HealthCheckListener => "Health Check Listener",
             ServerListener::ApiHealthCheckListener => "Api Health Check Listener",
         };
-        string.into()
+        f.write_str(string)
+    }
+}
+
+impl FromStr for ServerListener {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let name: String = input
+            .chars()
+            .filter(|c| !matches!(c, ' ' | '-' | '_'))
+            .collect();
+        match name.to_lowercase().as_str() {
+            "healthcheck" | "healthchecklistener" => Ok(ServerListener::HealthCheckListener),
+            "apihealthcheck" | "apihealthchecklistener" => {
+                Ok(ServerListener::ApiHealthCheckListener)
+            }
+            _ => Err(format!("unknown listener {}", input)),
+        }
     }
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize, Default)]
+//where the agent listens unless configured, and where balancers poll it
+pub const DEFAULT_AGENT_PORT: u16 = 3001;
+
+fn default_port() -> u16 {
+    DEFAULT_AGENT_PORT
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_metrics_interval_ms() -> u64 {
+    1000
+}
+
+//the application this node runs, registered with every balancer in
+//loadbalancer_ip on startup and deregistered on shutdown
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeRegistration {
+    pub url: String, //where balancers send its traffic, like http://10.0.0.1:8080
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>, //the registration.token of the balancers, they require one
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ServerConfig {
-    pub ip: String,                    //ip of the machine
-    pub listener: Vec<ServerListener>, //what request the server will listen to
-    pub loadbalancer_ip: Vec<String>,  // the
+    pub ip: String, //ip of the machine
+    #[serde(default = "default_port")]
+    pub port: u16, //with ip, where the agent listens when listen is empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub listen: Vec<String>, //addresses like 0.0.0.0:3001 or [::]:3001, replaces ip and port
+    pub listener: Vec<ServerListener>, //what the agent serves: /connections and /metrics, /apis
+    #[serde(default = "default_metrics_interval_ms")]
+    pub metrics_interval_ms: u64, //how often /metrics is sampled in the background
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_checks: Option<ApiConfig>, //this node's apis, probed from here for /apis
+    pub loadbalancer_ip: Vec<String>, //balancers as ip or ip:port of their admin address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registration: Option<NodeRegistration>, //none for nodes listed statically
 }
 
-pub async fn configure_server() {
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            ip: String::new(),
+            port: default_port(),
+            listen: vec![],
+            listener: vec![],
+            metrics_interval_ms: default_metrics_interval_ms(),
+            api_checks: None,
+            loadbalancer_ip: vec![],
+            registration: None,
+        }
+    }
+}
+
+//a balancer given by ip alone is only checked for being reachable, registering
+//needs the port of its admin listener
+fn balancer_address(balancer: &str) -> Option<SocketAddr> {
+    balancer.parse().ok().or_else(|| {
+        let ip: IpAddr = balancer.parse().ok()?;
+        Some(SocketAddr::new(ip, DEFAULT_PORT))
+    })
+}
+
+impl ServerConfig {
+    pub fn listen_addresses(&self) -> Vec<SocketAddr> {
+        listen_addresses(&self.ip, self.port, &self.listen)
+    }
+
+    pub fn balancer_addresses(&self) -> Vec<SocketAddr> {
+        self.loadbalancer_ip
+            .iter()
+            .filter_map(|balancer| balancer_address(balancer))
+            .collect()
+    }
+
+    //what is registered with the balancers, none when registration is off
+    pub fn node(&self) -> Option<Node> {
+        let registration = self.registration.as_ref()?;
+        let mut node: Node = registration.url.parse().ok()?;
+        node.agent_port = self.listen_addresses().first()?.port();
+        node.weight = registration.weight;
+        node.max_connections = registration.max_connections;
+        Some(node)
+    }
+
+    //every problem as (field path, message)
+    pub fn validate(&self) -> Vec<(String, String)> {
+        let mut errors = vec![];
+        if self.ip.parse::<IpAddr>().is_err() {
+            errors.push(("ip".to_string(), "must be an ip address".to_string()));
+        }
+        errors.extend(validate_listen(self.port, &self.listen));
+        if self.metrics_interval_ms == 0 {
+            errors.push((
+                "metrics_interval_ms".to_string(),
+                "must be greater than 0".to_string(),
+            ));
+        }
+        for (i, ip) in self.loadbalancer_ip.iter().enumerate() {
+            if balancer_address(ip).is_none() {
+                errors.push((
+                    format!("loadbalancer_ip[{}]", i),
+                    "must be an ip address or ip:port".to_string(),
+                ));
+            } else if self.registration.is_some() && ip.parse::<SocketAddr>().is_err() {
+                errors.push((
+                    format!("loadbalancer_ip[{}]", i),
+                    "needs the port of the balancer's admin_listen to register with, like 10.0.0.1:3002"
+                        .to_string(),
+                ));
+            }
+        }
+        match &self.api_checks {
+            Some(api_checks) => {
+                for (path, message) in api_checks.validate() {
+                    errors.push((format!("api_checks.{}", path), message));
+                }
+            }
+            None if self
+                .listener
+                .contains(&ServerListener::ApiHealthCheckListener) =>
+            {
+                errors.push((
+                    "listener".to_string(),
+                    "ApiHealthCheckListener needs an api_checks section".to_string(),
+                ));
+            }
+            None => {}
+        }
+        if let Some(registration) = &self.registration {
+            if let Err(e) = registration.url.parse::<Node>() {
+                errors.push(("registration.url".to_string(), e));
+            }
+            if registration.weight == 0 {
+                errors.push((
+                    "registration.weight".to_string(),
+                    "must be at least 1".to_string(),
+                ));
+            }
+            if self.loadbalancer_ip.is_empty() {
+                errors.push((
+                    "loadbalancer_ip".to_string(),
+                    "needs a balancer to register with".to_string(),
+                ));
+            }
+        }
+        errors
+    }
+}
+
+//the config saved by the wizard when no config file is given
+pub fn load_config() -> Result<ServerConfig, ConfyError> {
+    confy::load("server-config", None)
+}
+
+//writes the node section of a config file, or the saved config
+pub fn store_config(path: Option<&Path>, config: &ServerConfig) -> Result<(), String> {
+    match path {
+        Some(path) => cluster_config::update(path, "node", config),
+        None => confy::store("server-config", None, config).map_err(|e| format!("{:?}", e)),
+    }
+}
+
+//none when the entered config did not validate
+pub fn configure_server(path: Option<&Path>) -> Option<ServerConfig> {
     let ip: String = Input::new()
         .with_prompt("Enter the IP address of the server(tailscale ip)")
         .interact_text()
         .unwrap();
+    let port: u16 = Input::new()
+        .with_prompt("Node agent port")
+        .default(default_port())
+        .interact_text()
+        .unwrap();
 
     let listener = [
         ServerListener::HealthCheckListener,
@@ -49,27 +243,20 @@ pub async fn configure_server() {
         .iter()
         .map(|&index| listener[index].clone())
         .collect();
+    let api_checks = listener_selected
+        .contains(&ServerListener::ApiHealthCheckListener)
+        .then(configure_api_checks);
 
     let mut next_ip = true;
     let mut loadbalancer_ip: Vec<String> = vec![];
     while next_ip {
         let node_ip: String = Input::with_theme(&ColorfulTheme::default())
-            .with_prompt("Connected Loadbalancers")
+            .with_prompt("Connected Loadbalancers (ip, or admin ip:port to register with)")
             .validate_with(|input: &String| -> Result<(), &str> {
-                let numbers: Vec<&str> = input.split('.').collect();
-
-                if numbers.len() != 4 {
-                    return Err("IPv4 address must have exactly 4 octets");
+                match balancer_address(input) {
+                    Some(_) => Ok(()),
+                    None => Err("Not an IPv4 or IPv6 address"),
                 }
-
-                for num in numbers {
-                    match num.parse::<u8>() {
-                        Ok(_) => continue,
-                        Err(_) => return Err("Each octet must be a number between 0 and 255"),
-                    }
-                }
-
-                Ok(())
             })
             .interact_text()
             .unwrap();
@@ -85,17 +272,122 @@ pub async fn configure_server() {
         }
     }
 
+    let register = Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt("Register the application with the load balancers?")
+        .default(false)
+        .interact()
+        .unwrap();
+    let registration = register.then(|| {
+        let url: String = Input::with_theme(&ColorfulTheme::default())
+            .with_prompt("Application url (like http://10.0.0.1:8080)")
+            .validate_with(|input: &String| -> Result<(), String> {
+                input.parse::<Node>().map(|_| ())
+            })
+            .interact_text()
+            .unwrap();
+        let weight: u32 = Input::with_theme(&ColorfulTheme::default())
+            .with_prompt("Weight")
+            .default(default_weight())
+            .interact_text()
+            .unwrap();
+        NodeRegistration {
+            url,
+            weight,
+            max_connections: None,
+            token: None,
+        }
+    });
+
     let config = ServerConfig {
         ip,
+        port,
+        listen: vec![],
         listener: listener_selected,
+        metrics_interval_ms: default_metrics_interval_ms(),
+        api_checks,
         loadbalancer_ip,
+        registration,
     };
     println!("Server Config: {:#?}", config);
 
+    let errors = config.validate();
+    if !errors.is_empty() {
+        for (path, message) in errors {
+            println!("❌ {}: {}", path, message);
+        }
+        return None;
+    }
     if !validate_server_config(&config) {
         println!("Invalid configuration. Please check the IP addresses and try again.");
-        return;
+        return None;
+    }
+    store_config(path, &config).expect("Failed to store config");
+    Some(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::loadbalancer_config::Api;
+
+    fn config(loadbalancer_ip: &[&str]) -> ServerConfig {
+        ServerConfig {
+            ip: "10.0.0.1".to_string(),
+            loadbalancer_ip: loadbalancer_ip.iter().map(|ip| ip.to_string()).collect(),
+            registration: Some(NodeRegistration {
+                url: "http://10.0.0.1:8080".to_string(),
+                weight: 2,
+                max_connections: None,
+                token: None,
+            }),
+            ..ServerConfig::default()
+        }
+    }
+
+    #[test]
+    fn registration_needs_the_admin_port() {
+        let config = config(&["10.0.0.9:3002", "[fd00::9]:3002"]);
+        assert!(config.validate().is_empty());
+        let addresses: Vec<String> = config
+            .balancer_addresses()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(addresses, vec!["10.0.0.9:3002", "[fd00::9]:3002"]);
+
+        let mut bare = self::config(&["10.0.0.9:3002", "10.0.0.9"]);
+        let errors = bare.validate();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, "loadbalancer_ip[1]");
+        //without registration the balancers are only pinged
+        bare.registration = None;
+        assert!(bare.validate().is_empty());
+    }
+
+    #[test]
+    fn registration_announces_the_application() {
+        let node = config(&["10.0.0.9:3002"]).node().unwrap();
+        assert_eq!(node.address(), "10.0.0.1:8080");
+        assert_eq!(node.agent_port, DEFAULT_AGENT_PORT);
+        assert_eq!(node.weight, 2);
+
+        let errors = config(&[]).validate();
+        assert_eq!(errors[0].0, "loadbalancer_ip");
+    }
+
+    #[test]
+    fn api_listener_needs_apis() {
+        let mut config = config(&["10.0.0.9:3002"]);
+        config.listener = vec![ServerListener::ApiHealthCheckListener];
+        assert_eq!(config.validate()[0].0, "listener");
+        config.api_checks = Some(ApiConfig {
+            apis: vec![Api {
+                url: "/health".to_string(),
+                method: "GET".to_string(),
+                body: None,
+            }],
+            ..ApiConfig::default()
+        });
+        assert_eq!(config.validate()[0].0, "api_checks.apis[0].url");
     }
-    confy::store("server-config", None, config).expect("Failed to store config");
-    server_listener().await;
 }
@@ -1,8 +1,8 @@
-use crate::subapps::loadbalancer::Api;
+use crate::config::loadbalancer_config::Api;
 use axum::http::StatusCode;
 use sqlx::PgPool;
 
-pub async fn insert_apis(apis: &Vec<Api>, db: &PgPool) -> Result<(), sqlx::Error> {
+pub async fn insert_apis(apis: &[Api], db: &PgPool) -> Result<(), sqlx::Error> {
     for api in apis.iter() {
         sqlx::query!("insert into api_info (api) values ($1)", api.url)
             .execute(db)
@@ -29,19 +29,15 @@ pub async fn update_error_code(
         .fetch_one(db)
         .await?;
 
-    let errors = row.errors;
-    match errors {
-        Some(mut errors) => {
-            errors.push(status.as_u16() as i32);
-            sqlx::query!(
-                "update api_info set errors = ($1) where api = ($2)",
-                &errors,
-                path
-            )
-            .execute(db)
-            .await?;
-        }
-        None => {}
+    if let Some(mut errors) = row.errors {
+        errors.push(status.as_u16() as i32);
+        sqlx::query!(
+            "update api_info set errors = ($1) where api = ($2)",
+            &errors,
+            path
+        )
+        .execute(db)
+        .await?;
     }
 
     Ok(())
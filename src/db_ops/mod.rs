@@ -1 +1 @@
-pub mod lb_db;
\ No newline at end of file
+pub mod lb_db;
@@ -1,53 +1,296 @@
+mod cli;
 mod common;
 mod config;
 mod db_ops;
 mod subapps;
 mod validator;
+use clap::{CommandFactory, Parser};
+use confy::ConfyError;
 use dialoguer::{theme::ColorfulTheme, Confirm, Select};
 use log::{error, info};
-use std::io::{self, Write};
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::process;
 
+use crate::cli::{
+    Cli, Command, ConfigCommand, LbArgs, LbCommand, MicroArgs, MicroCommand, NodeArgs, NodeCommand,
+    ValidateTarget,
+};
+use crate::common::reload::Reload;
 use crate::common::utilities::{db_init, log_init};
+use crate::config::cluster_config::{self, ClusterConfig, DatabaseConfig, LoggingConfig};
+use crate::config::env;
 
 use crate::subapps::loadbalancer::balance_load;
+use crate::subapps::microserver::micro_server;
 use crate::subapps::node::server_listener;
+use crate::validator::validate::{validate_lb_config, validate_server_config};
 use config::loadbalancer_config::{
-    configure_load_balancer, Features, LoadBalancerConfig, Protocol,
+    self, configure_load_balancer, Features, LoadBalancerConfig, Protocol,
 };
-use config::server_config::{configure_server, ServerConfig};
+use config::microserver_config::{self, configure_micro_server, MicroServerConfig};
+use config::server_config::{self, configure_server, ServerConfig};
 enum NodeType {
     LoadBalancer,
     Server,
     MicroServer,
 }
 
-impl ToString for NodeType {
-    fn to_string(&self) -> String {
+impl fmt::Display for NodeType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let string = match self {
             NodeType::LoadBalancer => "Load Balancer",
             NodeType::Server => "Server",
             NodeType::MicroServer => "Micro Server",
         };
-        string.into()
+        f.write_str(string)
     }
 }
 
-
 #[tokio::main]
 async fn main() {
-
     io::stdout().flush().unwrap();
     dotenvy::dotenv().ok();
+    let cli = Cli::parse();
+
+    match cli.command {
+        Some(Command::Lb { command }) => load_balancer(command).await,
+        Some(Command::Node { command }) => node(command).await,
+        Some(Command::Micro { command }) => micro(command).await,
+        Some(Command::Validate { target }) => validate(target),
+        Some(Command::Config {
+            command: ConfigCommand::Check { path },
+        }) => {
+            read_config_file(&path);
+            println!("✅ {} is valid", path.display());
+        }
+        None if io::stdin().is_terminal() => {
+            log_init(&LoggingConfig::default());
+            interactive().await;
+        }
+        //prompts would block forever without a terminal
+        None => {
+            Cli::command().print_help().ok();
+            process::exit(2);
+        }
+    }
+}
+
+async fn load_balancer(command: LbCommand) {
+    match command {
+        LbCommand::Run(args) => {
+            let (cfg, cluster) = or_exit(read_lb_config(&args));
+            log_init(&cluster.logging);
+            run_load_balancer(cfg, &cluster.database, lb_reload(*args)).await;
+        }
+        LbCommand::Configure { config } => {
+            log_init(&LoggingConfig::default());
+            configure_lb(config.as_deref()).await;
+        }
+    }
+}
+
+async fn node(command: NodeCommand) {
+    match command {
+        NodeCommand::Run(args) => {
+            let (cfg, cluster) = or_exit(read_node_config(&args));
+            log_init(&cluster.logging);
+            server_listener(cfg).await;
+        }
+        NodeCommand::Configure { config } => {
+            log_init(&LoggingConfig::default());
+            if configure_server(config.as_deref()).is_none() {
+                process::exit(1);
+            }
+        }
+    }
+}
+
+async fn micro(command: MicroCommand) {
+    match command {
+        MicroCommand::Run(args) => {
+            let (cfg, cluster) = or_exit(read_micro_config(&args));
+            log_init(&cluster.logging);
+            micro_server(cfg).await;
+        }
+        MicroCommand::Configure { config } => {
+            log_init(&LoggingConfig::default());
+            if configure_micro_server(config.as_deref()).is_none() {
+                process::exit(1);
+            }
+        }
+    }
+}
+
+fn validate(target: ValidateTarget) {
+    let valid = match target {
+        ValidateTarget::Lb(args) => validate_lb_config(&or_exit(read_lb_config(&args)).0),
+        ValidateTarget::Node(args) => validate_server_config(&or_exit(read_node_config(&args)).0),
+    };
+    if !valid {
+        process::exit(1);
+    }
+}
+
+//reports every problem in the file and exits when there is one
+fn read_config_file(path: &Path) -> ClusterConfig {
+    match cluster_config::load(path) {
+        Ok(cluster) => cluster,
+        Err(errors) => {
+            for e in errors {
+                eprintln!("❌ {}: {}", path.display(), e);
+            }
+            process::exit(1);
+        }
+    }
+}
+
+//logging is not set up yet while the config loads, so problems go to stderr
+fn or_exit<T>(loaded: Result<T, Vec<String>>) -> T {
+    match loaded {
+        Ok(loaded) => loaded,
+        Err(errors) => {
+            for e in errors {
+                eprintln!("❌ {}", e);
+            }
+            process::exit(1);
+        }
+    }
+}
+
+//the layers under the flags: defaults, then the config file or the config saved
+//by the wizard, then CLUSTER_* variables
+fn read_layers(
+    path: Option<&Path>,
+    saved: impl FnOnce(&mut ClusterConfig) -> Result<(), ConfyError>,
+) -> Result<ClusterConfig, Vec<String>> {
+    let cluster = match path {
+        Some(path) => cluster_config::load(path).map_err(|errors| {
+            errors
+                .iter()
+                .map(|e| format!("{}: {}", path.display(), e))
+                .collect::<Vec<_>>()
+        })?,
+        None => {
+            let mut cluster = ClusterConfig::default();
+            saved(&mut cluster).map_err(|e| vec![format!("failed to load config: {:?}", e)])?;
+            cluster
+        }
+    };
+    env::apply(&cluster, std::env::vars())
+        .map_err(|errors| errors.iter().map(ToString::to_string).collect())
+}
+
+//checks the result of every layer, flags included
+fn check(cluster: &ClusterConfig) -> Result<(), Vec<String>> {
+    let errors: Vec<String> = cluster
+        .validate()
+        .into_iter()
+        .map(|(path, message)| format!("{}: {}", path, message))
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn read_lb_config(args: &LbArgs) -> Result<(LoadBalancerConfig, ClusterConfig), Vec<String>> {
+    let mut cluster = read_layers(args.config.as_deref(), |cluster| {
+        cluster.load_balancer = Some(loadbalancer_config::load_config()?);
+        Ok(())
+    })?;
+    let Some(mut cfg) = cluster.load_balancer.take() else {
+        return Err(vec!["the config has no load_balancer section".to_string()]);
+    };
+    args.apply(&mut cfg);
+    cluster.load_balancer = Some(cfg.clone());
+    check(&cluster)?;
+    Ok((cfg, cluster))
+}
+
+fn read_node_config(args: &NodeArgs) -> Result<(ServerConfig, ClusterConfig), Vec<String>> {
+    let mut cluster = read_layers(args.config.as_deref(), |cluster| {
+        cluster.node = Some(server_config::load_config()?);
+        Ok(())
+    })?;
+    let Some(mut cfg) = cluster.node.take() else {
+        return Err(vec!["the config has no node section".to_string()]);
+    };
+    args.apply(&mut cfg);
+    cluster.node = Some(cfg.clone());
+    check(&cluster)?;
+    Ok((cfg, cluster))
+}
+
+fn read_micro_config(args: &MicroArgs) -> Result<(MicroServerConfig, ClusterConfig), Vec<String>> {
+    let mut cluster = read_layers(args.config.as_deref(), |cluster| {
+        cluster.micro_server = Some(microserver_config::load_config()?);
+        Ok(())
+    })?;
+    let Some(mut cfg) = cluster.micro_server.take() else {
+        return Err(vec!["the config has no micro_server section".to_string()]);
+    };
+    args.apply(&mut cfg);
+    cluster.micro_server = Some(cfg.clone());
+    check(&cluster)?;
+    Ok((cfg, cluster))
+}
 
-    log_init();
-    let db = match db_init().await {
+//reads the same file with the same flags again, the flags keep winning
+fn lb_reload(args: LbArgs) -> Reload {
+    let path = match &args.config {
+        Some(path) => Some(path.clone()),
+        None => confy::get_configuration_file_path("load-balancer-config", None).ok(),
+    };
+    Reload {
+        path,
+        load: Box::new(move || read_lb_config(&args).map(|(cfg, _)| cfg)),
+    }
+}
+
+async fn run_load_balancer(cfg: LoadBalancerConfig, database: &DatabaseConfig, reload: Reload) {
+    let db = match db_init(database).await {
         Ok(pool) => pool,
         Err(e) => {
             error!("❌ Failed to connect to database: {e}");
-            std::process::exit(1);
+            process::exit(1);
         }
     };
+    balance_load(db, cfg, reload).await;
+}
+
+async fn configure_lb(path: Option<&Path>) -> LoadBalancerConfig {
+    let protocols = [
+        Protocol::RobinRound,
+        Protocol::LeastConnections,
+        Protocol::LeastResponse,
+        Protocol::ResourceAware,
+        Protocol::WeightedRobinRound,
+        Protocol::ConsistentHash,
+    ];
+    let features = [Features::HealthCheck, Features::ApiHealthCheck];
+    match configure_load_balancer(&protocols, &features, path).await {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    }
+}
 
+fn keep_old_config() -> bool {
+    Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt("want to continue with old config?")
+        .default(false)
+        .interact()
+        .unwrap()
+}
+
+//the original menu driven flow, what it runs is read back like `run` reads the
+//saved config, so CLUSTER_* variables apply and a broken config stops here
+async fn interactive() {
     let node_types = [
         NodeType::LoadBalancer,
         NodeType::Server,
@@ -64,60 +307,43 @@ async fn main() {
 
     match node_type {
         NodeType::LoadBalancer => {
-            let cfg_path: Result<std::path::PathBuf, confy::ConfyError> =
-                confy::get_configuration_file_path("load-balancer-config", None);
-            if cfg_path.is_ok() {
-                info!(
-                    "found a file config file for load-balancer at {:#?}",
-                    cfg_path
-                );
-                let cfg: LoadBalancerConfig =
-                    confy::load("load-balancer-config", None).expect("❌ failed to load config");
-                info!("{:#?}", cfg);
-                let proceed = Confirm::with_theme(&ColorfulTheme::default())
-                    .with_prompt("want to continue with old config?")
-                    .default(false)
-                    .interact()
-                    .unwrap();
-                if proceed {
-                    balance_load(db).await;
-                } else {
-                    let protocols = [
-                        Protocol::RobinRound,
-                        Protocol::LeastConnections,
-                        Protocol::LeastResponse,
-                    ];
-                    let features = [Features::HealthCheck, Features::ApiHealthCheck];
-                    match configure_load_balancer(&protocols, &features).await {
-                        Ok(()) => {}
-                        Err(e) => {
-                            error!("{}", e);
-                            std::process::exit(1);
-                        }
-                    };
-                    balance_load(db).await;
-                }
+            let cfg_path = confy::get_configuration_file_path("load-balancer-config", None);
+            info!(
+                "found a file config file for load-balancer at {:#?}",
+                cfg_path
+            );
+            let cfg = loadbalancer_config::load_config().expect("❌ failed to load config");
+            info!("{:#?}", cfg);
+            if !keep_old_config() {
+                configure_lb(None).await;
             }
+            let (cfg, cluster) = or_exit(read_lb_config(&LbArgs::default()));
+            run_load_balancer(cfg, &cluster.database, lb_reload(LbArgs::default())).await;
         }
         NodeType::Server => {
             let cfg_path = confy::get_configuration_file_path("server-config", None);
-            if cfg_path.is_ok() {
-                println!("found a file config file for server at {:#?}", cfg_path);
-                let cfg: ServerConfig =
-                    confy::load("server-config", None).expect("Failed to load config");
-                println!("{:#?}", cfg);
-                let proceed = Confirm::with_theme(&ColorfulTheme::default())
-                    .with_prompt("want to continue with old config?")
-                    .default(false)
-                    .interact()
-                    .unwrap();
-                if proceed {
-                    server_listener().await;
-                } else {
-                    configure_server().await;
-                }
+            println!("found a file config file for server at {:#?}", cfg_path);
+            let cfg = server_config::load_config().expect("Failed to load config");
+            println!("{:#?}", cfg);
+            if !keep_old_config() && configure_server(None).is_none() {
+                process::exit(1);
+            }
+            let (cfg, _) = or_exit(read_node_config(&NodeArgs::default()));
+            server_listener(cfg).await;
+        }
+        NodeType::MicroServer => {
+            let cfg_path = confy::get_configuration_file_path("micro-server-config", None);
+            println!(
+                "found a file config file for micro server at {:#?}",
+                cfg_path
+            );
+            let cfg = microserver_config::load_config().expect("Failed to load config");
+            println!("{:#?}", cfg);
+            if !keep_old_config() && configure_micro_server(None).is_none() {
+                process::exit(1);
             }
+            let (cfg, _) = or_exit(read_micro_config(&MicroArgs::default()));
+            micro_server(cfg).await;
         }
-        NodeType::MicroServer => {}
     };
 }
@@ -1,101 +1,677 @@
-use crate::config::loadbalancer_config::{Features, LoadBalancerConfig, Protocol};
+use crate::common::circuit_breaker::{BreakerState, CircuitBreaker};
+use crate::common::forwarding::{request_headers, response_headers};
+use crate::common::hash_ring::HashRing;
+use crate::common::health::NodeHealth;
+use crate::common::outlier::{now_millis, NodeOutlier};
+use crate::common::registration::{self, Deregistration, Lease, Registration};
+use crate::common::reload::{triggers, Reload};
+use crate::common::retry::{backoff, is_retryable, CountGuard, RetryBudget};
+use crate::common::serve::serve;
+use crate::common::sticky;
+use crate::config::loadbalancer_config::{
+    ApiConfig, CircuitBreakerConfig, Features, HashKey, HealthCheckConfig, LoadBalancerConfig,
+    MonitoringConfig, Node, OutlierDetectionConfig, Protocol, RegistrationConfig, RetryConfig,
+    StickySession, TimeoutConfig,
+};
 use crate::db_ops::lb_db::{insert_apis, update_error_code, update_hit};
-use crate::validator::validate::{read_json_from_file, validate_person_json};
 
 use axum::{
-    body::{to_bytes, Body},
-    extract::{Path, State},
-    http::{Request, Response, StatusCode},
-    routing::get,
-    Router,
+    body::{to_bytes, Body, Bytes, HttpBody},
+    extract::{ConnectInfo, State},
+    http::{
+        header::{CONTENT_LENGTH, SET_COOKIE},
+        request::Parts,
+        HeaderMap, Request, Response, StatusCode,
+    },
+    routing::{any, get, post},
+    Json, Router,
 };
+use futures_util::{stream, Stream, StreamExt};
 use log::{error, info, warn};
 use reqwest::Client;
 
 use crate::common::background::{api_health_check, health_check, load_balancer_connections};
-use serde::Deserialize;
-use serde_json;
+use serde::Serialize;
 use sqlx::PgPool;
-use std::collections::HashMap;
-use std::fs;
+use std::io;
+use std::net::SocketAddr;
 use std::{
-    sync::atomic::{AtomicUsize, Ordering},
-    sync::Arc,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::net::TcpListener;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{sleep, timeout};
 
-#[derive(Clone)]
+//one generation of the balancer, a reload builds a new one and swaps it in while
+//requests already in flight keep the generation they started with
 struct LoadBalancerState {
-    ip: String,
-    servers: Arc<Vec<String>>,
+    //bound once at startup, kept to notice when a reload changes them
+    listen: Vec<SocketAddr>,
+    admin_listen: Option<SocketAddr>,
+    servers: Arc<Vec<Node>>,
     protocol: Arc<Protocol>,
     features: Arc<Vec<Features>>,
     index: Arc<AtomicUsize>,
+    //live stats per node, same order as `servers`, kept across reloads by address
+    stats: Arc<Vec<Arc<NodeStats>>>,
+    //current weights for smooth weighted round robin, same order as `servers`
+    wrr_current: Arc<Mutex<Vec<i64>>>,
+    hash_key: Arc<HashKey>,
+    ring: Arc<HashRing>,
+    sticky_session: Arc<Option<StickySession>>,
+    sticky_secret: Arc<Vec<u8>>,
+    max_request_bytes: Option<u64>,
+    max_response_bytes: Option<u64>,
+    trust_forwarded_headers: bool,
+    health_check: Arc<HealthCheckConfig>,
+    outlier_detection: Arc<OutlierDetectionConfig>,
+    circuit_breaker: Arc<CircuitBreakerConfig>,
+    retry: Arc<RetryConfig>,
+    retry_budget: Arc<RetryBudget>,
+    timeouts: Arc<TimeoutConfig>,
+    monitoring: Arc<MonitoringConfig>,
     client: Client,
     db: PgPool,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct ApiConfig {
-    pub apis: Vec<Api>,
-    pub check_interval_ms: u64,
-    pub timeout_ms: u64,
-    pub failure_threshold: u32,
+//weight of the newest sample in the response time average
+const LATENCY_EWMA_ALPHA: f64 = 0.3;
+//chance of sending a request to a random node so slow or recovered nodes get re-measured
+const LATENCY_EXPLORATION: f64 = 0.1;
+//sample recorded when a node fails to answer at all
+const LATENCY_FAILURE_PENALTY: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+pub struct NodeStats {
+    connections: AtomicUsize,
+    //ewma of time to first byte in micros, stored as f64 bits, 0 until measured
+    latency: AtomicU64,
+    //load score between 0 and 1 from the last metrics sample, stored as f64 bits
+    load: AtomicU64,
+    pub health: NodeHealth,
+    pub outlier: NodeOutlier,
+    pub breaker: CircuitBreaker,
+}
+
+impl NodeStats {
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    pub fn latency_micros(&self) -> f64 {
+        f64::from_bits(self.latency.load(Ordering::SeqCst))
+    }
+
+    pub fn load(&self) -> f64 {
+        f64::from_bits(self.load.load(Ordering::SeqCst))
+    }
+
+    //cpu is a percentage, ram a fraction of total memory
+    pub fn record_load(&self, cpu: f64, ram: f64) {
+        let score = (0.5 * cpu / 100.0 + 0.5 * ram).clamp(0.0, 1.0);
+        self.load.store(score.to_bits(), Ordering::SeqCst);
+    }
+
+    //a node whose agent does not answer is treated as fully loaded
+    pub fn record_unreachable(&self) {
+        self.load.store(1.0_f64.to_bits(), Ordering::SeqCst);
+    }
+
+    fn record_latency(&self, elapsed: Duration) {
+        let sample = elapsed.as_micros() as f64;
+        let _ = self
+            .latency
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |bits| {
+                let current = f64::from_bits(bits);
+                let next = if current == 0.0 {
+                    sample
+                } else {
+                    LATENCY_EWMA_ALPHA * sample + (1.0 - LATENCY_EWMA_ALPHA) * current
+                };
+                Some(next.to_bits())
+            });
+    }
+}
+
+//an upstream response that is ready to be streamed back
+struct Upstream {
+    response: reqwest::Response,
+    connection: ConnectionGuard,
+    server_index: usize,
+    idle_timeout: Option<Duration>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
-pub struct Api {
-    pub url: String,
-    pub method: String,
-    #[serde(default)]
-    pub body: Option<HashMap<String, String>>,
+//holds a connection slot on a node for as long as a request is in flight
+struct ConnectionGuard {
+    stats: Arc<NodeStats>,
+}
+
+impl ConnectionGuard {
+    fn acquire(stats: &Arc<NodeStats>) -> Self {
+        stats.connections.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard {
+            stats: Arc::clone(stats),
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.stats.connections.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl LoadBalancerState {
-    fn new(db: PgPool) -> Self {
-        let cfg: LoadBalancerConfig =
-            confy::load("load-balancer-config", None).expect("failed to load config");
-        let ip = cfg.ip;
+    //`previous` is the generation being replaced, nodes it already knows keep
+    //their connections, health and breaker state
+    fn new(db: PgPool, cfg: LoadBalancerConfig, previous: Option<&LoadBalancerState>) -> Self {
+        let listen = cfg.listen_addresses();
+        let admin_listen = cfg.admin_address();
         let protocol = Arc::new(cfg.protocol);
         let features = Arc::new(cfg.features.clone());
         let index = Arc::new(AtomicUsize::new(0));
+        let stats: Arc<Vec<Arc<NodeStats>>> = Arc::new(
+            cfg.nodes
+                .iter()
+                .map(|node| {
+                    let known = previous.and_then(|lb| lb.stats_of(&node.address()));
+                    let stats = known.unwrap_or_default();
+                    if node.draining {
+                        stats.health.drain();
+                    } else {
+                        stats.health.resume();
+                    }
+                    stats
+                })
+                .collect(),
+        );
+        let wrr_current = Arc::new(Mutex::new(vec![0; cfg.nodes.len()]));
+        let hash_key = Arc::new(cfg.hash_key);
+        let ring = Arc::new(HashRing::new(&cfg.nodes));
+        //a random secret is kept across reloads so affinity cookies stay valid
+        let sticky_secret = match cfg.sticky_session.as_ref().and_then(|s| s.secret.clone()) {
+            Some(secret) => Arc::new(secret.into_bytes()),
+            None => match previous {
+                Some(lb) if lb.configured_secret().is_none() => Arc::clone(&lb.sticky_secret),
+                _ => Arc::new(rand::random::<[u8; 32]>().to_vec()),
+            },
+        };
+        let sticky_session = Arc::new(cfg.sticky_session);
         let servers = Arc::new(cfg.nodes);
 
         LoadBalancerState {
-            ip,
+            listen,
+            admin_listen,
             servers,
             protocol,
             features,
             index,
-            client: Client::new(),
+            stats,
+            wrr_current,
+            hash_key,
+            ring,
+            sticky_session,
+            sticky_secret,
+            max_request_bytes: cfg.max_request_bytes,
+            max_response_bytes: cfg.max_response_bytes,
+            trust_forwarded_headers: cfg.trust_forwarded_headers,
+            health_check: Arc::new(cfg.health_check),
+            outlier_detection: Arc::new(cfg.outlier_detection),
+            circuit_breaker: Arc::new(cfg.circuit_breaker),
+            retry: Arc::new(cfg.retry),
+            //in flight requests of the old generation still count against the budget
+            retry_budget: previous
+                .map(|lb| Arc::clone(&lb.retry_budget))
+                .unwrap_or_default(),
+            client: Client::builder()
+                .connect_timeout(Duration::from_millis(cfg.timeouts.connect_ms))
+                .build()
+                .expect("failed to build the client"),
+            timeouts: Arc::new(cfg.timeouts),
+            monitoring: Arc::new(cfg.monitoring),
             db,
         }
     }
 
-    async fn forward_request(&self, req: Request<Body>) -> Result<Response<String>, StatusCode> {
+    fn stats_of(&self, address: &str) -> Option<Arc<NodeStats>> {
+        self.servers
+            .iter()
+            .position(|node| node.address() == address)
+            .map(|i| Arc::clone(&self.stats[i]))
+    }
+
+    fn configured_secret(&self) -> Option<&String> {
+        self.sticky_session.as_ref().as_ref()?.secret.as_ref()
+    }
+
+    //none when every node is at its connection cap
+    fn select_server(&self, parts: &Parts, client: SocketAddr) -> Option<usize> {
+        //with registration on, the node list can be empty until agents announce themselves
+        if self.servers.is_empty() {
+            return None;
+        }
+        match *self.protocol {
+            Protocol::RobinRound => self.robin_round(),
+            Protocol::LeastConnections => self.least_connections(),
+            Protocol::LeastResponse => self.least_response(),
+            Protocol::ResourceAware => self.resource_aware(),
+            Protocol::WeightedRobinRound => self.weighted_robin_round(),
+            Protocol::ConsistentHash => self.consistent_hash(parts, client),
+        }
+    }
+
+    //whether a node is in rotation and can take one more request
+    fn is_available(&self, i: usize) -> bool {
+        let stats = &self.stats[i];
+        let now = now_millis();
+        if !stats.health.is_healthy()
+            || stats.outlier.is_ejected(now)
+            || !stats.breaker.allows(now, &self.circuit_breaker)
+        {
+            return false;
+        }
+        if self.circuit_breaker.enabled && stats.connections() >= self.circuit_breaker.max_requests
+        {
+            return false;
+        }
+        match self.servers[i].max_connections {
+            Some(max) => self.stats[i].connections() < max,
+            None => true,
+        }
+    }
+
+    fn robin_round(&self) -> Option<usize> {
+        let len = self.servers.len();
+        let start = self.index.fetch_add(1, Ordering::SeqCst) % len;
+        (0..len)
+            .map(|offset| (start + offset) % len)
+            .find(|&i| self.is_available(i))
+    }
+
+    //available node with the lowest score, ties are broken round robin
+    fn lowest_by(&self, score: impl Fn(usize) -> f64) -> Option<usize> {
+        let len = self.servers.len();
+        let start = self.index.fetch_add(1, Ordering::SeqCst) % len;
+        let mut selected = None;
+        let mut lowest = f64::MAX;
+        for offset in 0..len {
+            let i = (start + offset) % len;
+            if !self.is_available(i) {
+                continue;
+            }
+            let value = score(i);
+            if selected.is_none() || value < lowest {
+                lowest = value;
+                selected = Some(i);
+            }
+        }
+        selected
+    }
+
+    //node with the fewest in-flight requests relative to its weight
+    fn least_connections(&self) -> Option<usize> {
+        self.lowest_by(|i| self.stats[i].connections() as f64 / self.servers[i].weight as f64)
+    }
+
+    //node with the lowest average time to first byte, with some random exploration
+    fn least_response(&self) -> Option<usize> {
+        if rand::random::<f64>() < LATENCY_EXPLORATION {
+            let available: Vec<usize> = (0..self.servers.len())
+                .filter(|&i| self.is_available(i))
+                .collect();
+            if !available.is_empty() {
+                return Some(available[rand::random_range(0..available.len())]);
+            }
+        }
+        self.lowest_by(|i| self.stats[i].latency_micros())
+    }
+
+    //node with the lowest cpu/ram load reported by its agent
+    fn resource_aware(&self) -> Option<usize> {
+        self.lowest_by(|i| self.stats[i].load())
+    }
+
+    //smooth weighted round robin as done by nginx: every pick raises each node
+    //by its weight and lowers the chosen one by the total, which interleaves
+    //heavy and light nodes instead of sending bursts to the heaviest
+    fn weighted_robin_round(&self) -> Option<usize> {
+        let mut current = self.wrr_current.lock().unwrap();
+        let mut total = 0;
+        let mut selected: Option<usize> = None;
+        for i in 0..self.servers.len() {
+            if !self.is_available(i) {
+                continue;
+            }
+            let weight = self.servers[i].weight as i64;
+            current[i] += weight;
+            total += weight;
+            if selected.is_none_or(|s| current[i] > current[s]) {
+                selected = Some(i);
+            }
+        }
+        if let Some(i) = selected {
+            current[i] -= total;
+        }
+        selected
+    }
+
+    //node owning the request's key on the hash ring, or the next one clockwise if it is full
+    fn consistent_hash(&self, parts: &Parts, client: SocketAddr) -> Option<usize> {
+        let key = self.request_key(parts, client);
+        self.ring
+            .get_available(key.as_bytes(), |i| self.is_available(i))
+    }
+
+    //requests missing the configured header or cookie fall back to the client ip
+    fn request_key(&self, parts: &Parts, client: SocketAddr) -> String {
+        let key = match &*self.hash_key {
+            HashKey::ClientIp => None,
+            HashKey::Header(name) => parts
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            HashKey::Cookie(name) => cookie(parts, name).map(str::to_string),
+            HashKey::PathPrefix(segments) => Some(
+                parts
+                    .uri
+                    .path()
+                    .split('/')
+                    .filter(|segment| !segment.is_empty())
+                    .take(*segments)
+                    .collect::<Vec<_>>()
+                    .join("/"),
+            ),
+        };
+        key.unwrap_or_else(|| client.ip().to_string())
+    }
+
+    //feeds the result of a proxied request into the breaker and outlier detection
+    fn record_outcome(&self, server_index: usize, failed: bool) {
+        let breaker = &self.stats[server_index].breaker;
+        if let Some(state) = breaker.record(now_millis(), failed, &self.circuit_breaker) {
+            let address = self.servers[server_index].address();
+            match state {
+                BreakerState::Open => warn!("circuit breaker for {} is {}", address, state),
+                _ => info!("circuit breaker for {} is {}", address, state),
+            }
+        }
+
+        let cfg = &self.outlier_detection;
+        let outlier = &self.stats[server_index].outlier;
+        if !failed {
+            outlier.record_success();
+            return;
+        }
+        if !cfg.enabled || !outlier.record_failure(cfg.consecutive_failures) {
+            return;
+        }
+        let now = now_millis();
+        let ejected = self
+            .stats
+            .iter()
+            .filter(|stats| stats.outlier.is_ejected(now))
+            .count();
+        let over_limit =
+            (ejected + 1) * 100 > cfg.max_ejection_percent as usize * self.servers.len();
+        if ejected > 0 && over_limit {
+            warn!(
+                "not ejecting {}, {} nodes are already ejected",
+                self.servers[server_index].address(),
+                ejected
+            );
+            return;
+        }
+        let duration = outlier.eject(now, cfg);
+        warn!(
+            "ejecting {} for {:?} after {} failed requests in a row",
+            self.servers[server_index].address(),
+            duration,
+            cfg.consecutive_failures
+        );
+    }
+
+    //node named by a valid affinity cookie, as long as it can still take requests
+    fn sticky_node(&self, parts: &Parts) -> Option<usize> {
+        let session = self.sticky_session.as_ref().as_ref()?;
+        let value = cookie(parts, &session.cookie_name)?;
+        let node = sticky::verify(&self.sticky_secret, value, unix_now())?;
+        self.servers
+            .iter()
+            .position(|server| server.address() == node)
+            .filter(|&i| self.is_available(i))
+    }
+
+    fn affinity_cookie(&self, session: &StickySession, server_index: usize) -> String {
+        let expires = unix_now() + session.ttl_secs;
+        let value = sticky::sign(
+            &self.sticky_secret,
+            &self.servers[server_index].address(),
+            expires,
+        );
+        format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly",
+            session.cookie_name, value, session.ttl_secs
+        )
+    }
+
+    //one attempt against one node, returns the response once its headers are in
+    async fn try_node(
+        &self,
+        server_index: usize,
+        request: reqwest::RequestBuilder,
+        too_large: Option<Arc<AtomicBool>>,
+    ) -> Result<(reqwest::Response, ConnectionGuard), StatusCode> {
+        let server_url = self.servers[server_index].address();
+        let pending = match self.stats[server_index]
+            .breaker
+            .acquire(now_millis(), &self.circuit_breaker)
+        {
+            Ok(pending) => pending,
+            Err(rejection) => {
+                warn!(
+                    "circuit breaker refused request to {}: {:?}",
+                    server_url, rejection
+                );
+                return Err(StatusCode::SERVICE_UNAVAILABLE);
+            }
+        };
+        let connection = ConnectionGuard::acquire(&self.stats[server_index]);
+
+        let started = Instant::now();
+        let send = request.send();
+        let result = match self.retry.per_try_timeout_ms {
+            Some(ms) => timeout(Duration::from_millis(ms), send).await,
+            None => Ok(send.await),
+        };
+        drop(pending);
+        match result {
+            Ok(Ok(response)) => {
+                self.stats[server_index].record_latency(started.elapsed());
+                self.record_outcome(server_index, response.status().is_server_error());
+                Ok((response, connection))
+            }
+            Ok(Err(_)) if too_large.is_some_and(|flag| flag.load(Ordering::SeqCst)) => {
+                //the client's fault, the node proved nothing either way
+                self.stats[server_index].breaker.release();
+                Err(StatusCode::PAYLOAD_TOO_LARGE)
+            }
+            Ok(Err(e)) if !e.is_timeout() => {
+                self.stats[server_index].record_latency(LATENCY_FAILURE_PENALTY);
+                self.record_outcome(server_index, true);
+                warn!("request to {} failed: {}", server_url, e);
+                Err(StatusCode::BAD_GATEWAY)
+            }
+            _ => {
+                self.stats[server_index].record_latency(LATENCY_FAILURE_PENALTY);
+                self.record_outcome(server_index, true);
+                warn!("request to {} timed out", server_url);
+                Err(StatusCode::GATEWAY_TIMEOUT)
+            }
+        }
+    }
+
+    //next untried node for a retry, round robin among the available ones
+    fn select_retry(&self, tried: &[usize]) -> Option<usize> {
+        let len = self.servers.len();
+        let start = self.index.fetch_add(1, Ordering::SeqCst) % len;
+        (0..len)
+            .map(|offset| (start + offset) % len)
+            .find(|&i| !tried.contains(&i) && self.is_available(i))
+    }
+
+    async fn forward_request(
+        &self,
+        req: Request<Body>,
+        client: SocketAddr,
+    ) -> Result<Response<Body>, StatusCode> {
+        if self.servers.is_empty() {
+            error!("no nodes configured, cannot forward request");
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
         let (parts, body) = req.into_parts();
+        if exceeds(content_length(&parts.headers), self.max_request_bytes) {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        let _request = self.retry_budget.request();
 
-        let original_path = parts.uri.path();
-        let server_index = self.index.fetch_add(1, Ordering::SeqCst) % self.servers.len();
-        let server_url = &self.servers[server_index];
+        let path_and_query = parts
+            .uri
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+        let headers = request_headers(&parts.headers, client, self.trust_forwarded_headers);
+        let (request_timeout, idle_timeout) = self.timeouts.for_path(parts.uri.path());
+
+        //a retried request must be sent again, so small idempotent bodies are
+        //buffered and everything else is streamed upstream as it arrives. a
+        //request that came without a body goes out without one, not chunked
+        let empty = body.is_end_stream();
+        let retryable =
+            self.retry.max_retries > 0 && is_retryable(&parts.method, &parts.headers, &self.retry);
+        let mut replay: Option<Bytes> = None;
+        let mut stream: Option<Body> = None;
+        if empty {
+            //nothing to buffer or stream
+        } else if retryable && replayable(&parts.headers, self.retry.max_replay_bytes) {
+            let limit = self.retry.max_replay_bytes as usize;
+            replay = Some(
+                to_bytes(body, limit)
+                    .await
+                    .map_err(|_| StatusCode::BAD_REQUEST)?,
+            );
+        } else {
+            stream = Some(body);
+        }
+        let resendable = retryable && (empty || replay.is_some());
 
-        let uri = format!("{}{}", server_url, parts.uri);
-        let method = parts.method.clone();
-        let body = to_bytes(body, usize::MAX)
-            .await
-            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        let pinned = self.sticky_node(&parts);
+        let Some(mut server_index) = pinned.or_else(|| self.select_server(&parts, client)) else {
+            warn!("no healthy node below its connection limit");
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        };
+        let mut tried: Vec<usize> = vec![];
+        let mut _retry: Option<CountGuard> = None;
+        loop {
+            tried.push(server_index);
+
+            let uri = self.servers[server_index].upstream_url(path_and_query);
+            let mut request = self
+                .client
+                .request(parts.method.clone(), &uri)
+                .headers(headers.clone());
+            if let Some(request_timeout) = request_timeout {
+                request = request.timeout(request_timeout);
+            }
+            let (request, too_large) = match (&replay, stream.take()) {
+                (Some(bytes), _) => (request.body(bytes.clone()), None),
+                (None, Some(body)) => {
+                    let too_large = Arc::new(AtomicBool::new(false));
+                    let body = reqwest::Body::wrap_stream(limit_stream(
+                        body.into_data_stream(),
+                        self.max_request_bytes,
+                        Arc::clone(&too_large),
+                    ));
+                    (request.body(body), Some(too_large))
+                }
+                (None, None) if empty => (request, None),
+                (None, None) => return Err(StatusCode::BAD_GATEWAY),
+            };
+
+            let retries_left = resendable && tried.len() <= self.retry.max_retries as usize;
+            //a retryable answer is held until another node can take the request,
+            //so the client still gets its body and headers when none can
+            let (failure, held) = match self.try_node(server_index, request, too_large).await {
+                Ok((response, connection)) => {
+                    let status = response.status();
+                    let upstream = Upstream {
+                        response,
+                        connection,
+                        server_index,
+                        idle_timeout,
+                    };
+                    if !(retries_left && self.retry.retry_on_status.contains(&status.as_u16())) {
+                        return self.respond(upstream, pinned, &parts, &uri).await;
+                    }
+                    (status, Some(upstream))
+                }
+                Err(StatusCode::PAYLOAD_TOO_LARGE) => return Err(StatusCode::PAYLOAD_TOO_LARGE),
+                Err(status) if !retries_left => return Err(status),
+                Err(status) => (status, None),
+            };
+
+            let next = self.select_retry(&tried);
+            let retry = match next {
+                Some(_) => self.retry_budget.try_retry(&self.retry),
+                None => None,
+            };
+            let (Some(next), Some(retry)) = (next, retry) else {
+                match next {
+                    Some(_) => warn!("retry budget exhausted, giving up on {}", uri),
+                    None => warn!("no other node to retry {} on", uri),
+                }
+                return match held {
+                    Some(upstream) => self.respond(upstream, pinned, &parts, &uri).await,
+                    None => Err(failure),
+                };
+            };
+            drop(held);
+            _retry = Some(retry);
+            server_index = next;
+            let wait = backoff(tried.len() as u32 - 1, &self.retry);
+            info!(
+                "retrying {} {} after {} in {:?}",
+                parts.method, path_and_query, failure, wait
+            );
+            sleep(wait).await;
+        }
+    }
 
-        let response = self
-            .client
-            .request(method, &uri)
-            .body(body)
-            .send()
-            .await
-            .map_err(|_| StatusCode::BAD_GATEWAY)?;
+    //streams an upstream response back to the client
+    async fn respond(
+        &self,
+        upstream: Upstream,
+        pinned: Option<usize>,
+        parts: &Parts,
+        uri: &str,
+    ) -> Result<Response<Body>, StatusCode> {
+        let Upstream {
+            response,
+            connection,
+            server_index,
+            idle_timeout,
+        } = upstream;
+        if exceeds(response.content_length(), self.max_response_bytes) {
+            warn!("response from {} is over the size limit", uri);
+            return Err(StatusCode::BAD_GATEWAY);
+        }
 
         let status = response.status();
+        let headers = response_headers(response.headers());
         if !status.is_success() {
-            match update_error_code(&original_path, &status, &self.db).await {
+            match update_error_code(parts.uri.path(), &status, &self.db).await {
                 Ok(()) => {}
                 Err(e) => {
                     warn!("error code failed to update, moitering might not work as expected!");
@@ -104,76 +680,464 @@ impl LoadBalancerState {
             }
         }
         info!("status code for url {}: {}", uri, status);
-        let body = response.text().await.unwrap_or_else(|_| "".to_string());
+        //the response is streamed back as it arrives, the node keeps its
+        //connection slot until the last chunk has been sent
+        let body = limit_stream(
+            idle_stream(response.bytes_stream(), idle_timeout),
+            self.max_response_bytes,
+            Arc::new(AtomicBool::new(false)),
+        )
+        .map(move |chunk| {
+            let _ = &connection;
+            chunk
+        });
+        let body = Body::from_stream(body);
 
-        Ok(Response::builder().status(status).body(body).unwrap())
+        let mut builder = Response::builder().status(status);
+        if let Some(builder_headers) = builder.headers_mut() {
+            *builder_headers = headers;
+        }
+        if let Some(session) = self.sticky_session.as_ref() {
+            if pinned != Some(server_index) {
+                builder = builder.header(SET_COOKIE, self.affinity_cookie(session, server_index));
+            }
+        }
+        Ok(builder.body(body).unwrap())
     }
 }
 
-pub async fn balance_load(db: PgPool) {
-    //todo make switch to protocol
-    let load_balancer_state = LoadBalancerState::new(db);
-    let address = load_balancer_state.clone().ip + ":3000";
-    let servers = load_balancer_state.clone().servers;
-    tokio::spawn(load_balancer_connections());
-    if load_balancer_state
-        .features
-        .contains(&Features::HealthCheck)
-    {
-        tokio::spawn(health_check(servers));
-    }
+fn content_length(headers: &axum::http::HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
 
-    if load_balancer_state
-        .features
-        .contains(&Features::ApiHealthCheck)
-    {
-        let file_path = "./src/subapps/api.json";
+//bodies known to fit the replay buffer. without a content-length, as with
+//chunked and http/2 bodies, the size is unknown and the body is streamed
+fn replayable(headers: &axum::http::HeaderMap, max_bytes: u64) -> bool {
+    content_length(headers).is_some_and(|length| length <= max_bytes)
+}
 
-        match read_json_from_file(file_path) {
-            Ok(json_str) => match validate_person_json(&json_str) {
-                Ok(config) => info!("✅ Valid JSON: {:#?}", config),
-                Err(e) => error!("❌ Invalid JSON structure: {}", e),
+fn exceeds(length: Option<u64>, limit: Option<u64>) -> bool {
+    matches!((length, limit), (Some(length), Some(limit)) if length > limit)
+}
+
+//passes chunks through untouched and errors out once more than `limit` bytes
+//have gone by, flagging `too_large` so the caller can tell it apart from io errors
+fn limit_stream<E: std::fmt::Display>(
+    stream: impl Stream<Item = Result<Bytes, E>>,
+    limit: Option<u64>,
+    too_large: Arc<AtomicBool>,
+) -> impl Stream<Item = Result<Bytes, io::Error>> {
+    let mut seen: u64 = 0;
+    stream.map(move |chunk| {
+        let chunk = chunk.map_err(|e| io::Error::other(e.to_string()))?;
+        seen += chunk.len() as u64;
+        if exceeds(Some(seen), limit) {
+            too_large.store(true, Ordering::SeqCst);
+            return Err(io::Error::other("body is over the size limit"));
+        }
+        Ok(chunk)
+    })
+}
+
+//errors out when the upstream goes quiet for longer than `idle`
+fn idle_stream<E: std::fmt::Display>(
+    stream: impl Stream<Item = Result<Bytes, E>> + Send + 'static,
+    idle: Option<Duration>,
+) -> impl Stream<Item = Result<Bytes, io::Error>> {
+    stream::unfold(Box::pin(stream), move |mut stream| async move {
+        let next = match idle {
+            Some(idle) => match timeout(idle, stream.next()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    warn!("upstream was idle for {:?}, closing the response", idle);
+                    return Some((Err(io::Error::from(io::ErrorKind::TimedOut)), stream));
+                }
             },
-            Err(e) => error!("❌ Failed to read file: {}", e),
+            None => stream.next().await,
+        };
+        next.map(|chunk| (chunk.map_err(|e| io::Error::other(e.to_string())), stream))
+    })
+}
+
+fn cookie<'a>(parts: &'a Parts, name: &str) -> Option<&'a str> {
+    parts
+        .headers
+        .get_all("cookie")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(cookie, _)| *cookie == name)
+        .map(|(_, value)| value)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+//what the router and the background tasks hold, the lock is only taken to
+//clone or replace the current generation
+#[derive(Clone)]
+pub struct Balancer {
+    current: Arc<RwLock<Arc<LoadBalancerState>>>,
+    //held while a new generation is built so reloads and registrations queue up
+    registry: Arc<Mutex<Registry>>,
+}
+
+//the config behind the current generation and the nodes registered on top of it
+struct Registry {
+    config: LoadBalancerConfig,
+    registered: Vec<Registered>,
+}
+
+#[derive(Clone)]
+struct Registered {
+    node: Node,
+    seen_ms: u64, //when its agent last registered or renewed
+}
+
+impl Registry {
+    //registered nodes never replace a static node with the same address
+    fn config(&self) -> LoadBalancerConfig {
+        let mut cfg = self.config.clone();
+        for Registered { node, .. } in &self.registered {
+            if !cfg.nodes.iter().any(|n| n.address() == node.address()) {
+                cfg.nodes.push(node.clone());
+            }
         }
+        cfg
+    }
 
-        let data = fs::read_to_string(file_path).expect("unable to read file");
+    fn lease(&self) -> Lease {
+        Lease {
+            ttl_ms: self.config.registration.ttl_ms,
+        }
+    }
+}
 
-        let api_config: ApiConfig = serde_json::from_str(&data).expect("Unable to parse JSON");
-        let apis = api_config.apis.clone();
+//the nodes the health check probes, read again before every round
+pub struct HealthTargets {
+    pub servers: Arc<Vec<Node>>,
+    pub stats: Arc<Vec<Arc<NodeStats>>>,
+    pub cfg: Arc<HealthCheckConfig>,
+    pub monitoring: Arc<MonitoringConfig>,
+    //resource aware balancing is fed by the health check samples
+    pub enabled: bool,
+}
 
-        for api in apis.iter() {
-            info!("{:#?}", api);
+impl Balancer {
+    fn new(db: PgPool, cfg: LoadBalancerConfig) -> Self {
+        let state = LoadBalancerState::new(db, cfg.clone(), None);
+        Balancer {
+            current: Arc::new(RwLock::new(Arc::new(state))),
+            registry: Arc::new(Mutex::new(Registry {
+                config: cfg,
+                registered: vec![],
+            })),
         }
+    }
 
-        match insert_apis(&apis, &load_balancer_state.db).await {
-            Ok(()) => {
-                tokio::spawn(api_health_check(api_config));
-            }
-            Err(e) => {
-                warn!("apis not inserted, monitering might not work as expected");
-                error!("{}", e);
-                tokio::spawn(api_health_check(api_config));
+    fn current(&self) -> Arc<LoadBalancerState> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    pub fn health_targets(&self) -> HealthTargets {
+        let lb = self.current();
+        HealthTargets {
+            servers: Arc::clone(&lb.servers),
+            stats: Arc::clone(&lb.stats),
+            cfg: Arc::clone(&lb.health_check),
+            monitoring: Arc::clone(&lb.monitoring),
+            enabled: lb.features.contains(&Features::HealthCheck)
+                || matches!(*lb.protocol, Protocol::ResourceAware),
+        }
+    }
+
+    pub fn monitoring(&self) -> Arc<MonitoringConfig> {
+        Arc::clone(&self.current().monitoring)
+    }
+
+    //requests already in flight finish on the generation they started with
+    fn swap(&self, registry: &Registry) -> Arc<LoadBalancerState> {
+        let current = self.current();
+        let next = Arc::new(LoadBalancerState::new(
+            current.db.clone(),
+            registry.config(),
+            Some(&current),
+        ));
+        *self.current.write().unwrap() = Arc::clone(&next);
+        next
+    }
+
+    //registered nodes are kept
+    fn reload(&self, cfg: LoadBalancerConfig) {
+        let mut registry = self.registry.lock().unwrap();
+        let current = self.current();
+        if cfg.listen_addresses() != current.listen || cfg.admin_address() != current.admin_listen {
+            warn!("listen addresses changed, restart to listen on them");
+        }
+        if cfg.timeouts.header_read_ms != current.timeouts.header_read_ms
+            || cfg.timeouts.drain_ms != current.timeouts.drain_ms
+        {
+            warn!("timeouts.header_read_ms or drain_ms changed, restart to apply them");
+        }
+        registry.config = cfg;
+        let next = self.swap(&registry);
+        info!(
+            "config reloaded: {} nodes, protocol: {}, features: {:?}",
+            next.servers.len(),
+            next.protocol,
+            next.features
+        );
+    }
+
+    fn registration(&self) -> RegistrationConfig {
+        self.registry.lock().unwrap().config.registration.clone()
+    }
+
+    //adds or updates a node, an unchanged registration is a heartbeat that only
+    //renews its lease and leaves the generation alone
+    fn register(
+        &self,
+        registration: Registration,
+        now: u64,
+    ) -> Result<Lease, (StatusCode, String)> {
+        let mut registry = self.registry.lock().unwrap();
+        let node = registration.node;
+        let address = node.address();
+        if registry.config.nodes.iter().any(|n| n.address() == address) {
+            return Err((
+                StatusCode::CONFLICT,
+                format!("{} is a static node", address),
+            ));
+        }
+        let known = registry
+            .registered
+            .iter()
+            .position(|r| r.node.address() == address);
+        if let Some(i) = known.filter(|&i| registry.registered[i].node == node) {
+            registry.registered[i].seen_ms = now;
+            return Ok(registry.lease());
+        }
+
+        let mut registered = registry.registered.clone();
+        let entry = Registered { node, seen_ms: now };
+        match known {
+            Some(i) => registered[i] = entry,
+            None => registered.push(entry),
+        }
+        let candidate = Registry {
+            config: registry.config.clone(),
+            registered,
+        };
+        let errors = candidate.config().validate();
+        if !errors.is_empty() {
+            let errors: Vec<String> = errors
+                .into_iter()
+                .map(|(path, message)| format!("{}: {}", path, message))
+                .collect();
+            return Err((StatusCode::BAD_REQUEST, errors.join(", ")));
+        }
+        *registry = candidate;
+        self.swap(&registry);
+        let verb = if known.is_some() {
+            "updated"
+        } else {
+            "registered"
+        };
+        info!("{} {} {}", registration.name, verb, address);
+        Ok(registry.lease())
+    }
+
+    //false when no node was registered at that address, static nodes stay
+    fn deregister(&self, address: &str) -> bool {
+        let mut registry = self.registry.lock().unwrap();
+        let before = registry.registered.len();
+        registry.registered.retain(|r| r.node.address() != address);
+        if registry.registered.len() == before {
+            return false;
+        }
+        self.swap(&registry);
+        info!("{} deregistered", address);
+        true
+    }
+
+    //drops the nodes whose lease ran out, like an agent that died without
+    //deregistering
+    fn expire(&self, now: u64) {
+        let mut registry = self.registry.lock().unwrap();
+        let ttl_ms = registry.config.registration.ttl_ms;
+        let (live, expired): (Vec<Registered>, Vec<Registered>) = registry
+            .registered
+            .drain(..)
+            .partition(|r| now.saturating_sub(r.seen_ms) < ttl_ms);
+        registry.registered = live;
+        if expired.is_empty() {
+            return;
+        }
+        self.swap(&registry);
+        for Registered { node, .. } in expired {
+            warn!("{} missed its heartbeats and was removed", node.address());
+        }
+    }
+}
+
+fn wants_api_checks(cfg: &LoadBalancerConfig) -> Option<ApiConfig> {
+    if !cfg.features.contains(&Features::ApiHealthCheck) {
+        return None;
+    }
+    if cfg.api_checks.is_none() {
+        error!("api health check is on but no api_checks are configured");
+    }
+    cfg.api_checks.clone()
+}
+
+async fn start_api_checks(api_config: ApiConfig, balancer: &Balancer) -> JoinHandle<()> {
+    let db = &balancer.current().db;
+    for api in api_config.apis.iter() {
+        info!("{:#?}", api);
+    }
+    if let Err(e) = insert_apis(&api_config.apis, db).await {
+        warn!("apis not inserted, monitering might not work as expected");
+        error!("{}", e);
+    }
+    tokio::spawn(api_health_check(api_config, balancer.clone()))
+}
+
+//a config that fails to load or validate is logged and the current one stays
+async fn reload_on_change(
+    balancer: Balancer,
+    reload: Reload,
+    mut api_checks: Option<ApiConfig>,
+    mut api_task: Option<JoinHandle<()>>,
+) {
+    let mut triggers = triggers(reload.path);
+    while let Some(trigger) = triggers.recv().await {
+        info!("reloading config: {}", trigger);
+        let cfg = match (reload.load)() {
+            Ok(cfg) => cfg,
+            Err(errors) => {
+                error!("config reload failed, keeping the current config");
+                for e in errors {
+                    error!("{}", e);
+                }
+                continue;
             }
         };
+        let next_api_checks = wants_api_checks(&cfg);
+        balancer.reload(cfg);
+
+        if next_api_checks != api_checks {
+            if let Some(task) = api_task.take() {
+                task.abort();
+            }
+            if let Some(api_config) = next_api_checks.clone() {
+                api_task = Some(start_api_checks(api_config, &balancer).await);
+            }
+            api_checks = next_api_checks;
+        }
+    }
+}
+
+pub async fn balance_load(db: PgPool, cfg: LoadBalancerConfig, reload: Reload) {
+    let api_checks = wants_api_checks(&cfg);
+    let balancer = Balancer::new(db, cfg);
+    let lb = balancer.current();
+    info!("balancing with protocol: {}", lb.protocol);
+    let header_read_timeout = Duration::from_millis(lb.timeouts.header_read_ms);
+    let drain = Duration::from_millis(lb.timeouts.drain_ms);
+    tokio::spawn(load_balancer_connections(balancer.clone()));
+    tokio::spawn(health_check(balancer.clone()));
+
+    let api_task = match api_checks.clone() {
+        Some(api_config) => Some(start_api_checks(api_config, &balancer).await),
+        None => None,
+    };
+    tokio::spawn(expire_registrations(balancer.clone()));
+    tokio::spawn(reload_on_change(
+        balancer.clone(),
+        reload,
+        api_checks,
+        api_task,
+    ));
+
+    let traffic = proxy_routes().with_state(balancer.clone());
+
+    let mut servers = JoinSet::new();
+    for address in lb.listen.iter() {
+        let listener = bind(*address).await;
+        info!("loadbalancer is listening on {}", address);
+        servers.spawn(serve(
+            listener,
+            traffic.clone(),
+            header_read_timeout,
+            drain,
+            registration::shutdown(),
+        ));
+    }
+    match lb.admin_listen {
+        Some(address) => {
+            let listener = bind(address).await;
+            info!("admin endpoints are on {}", address);
+            let admin = admin_routes().with_state(balancer.clone());
+            servers.spawn(serve(
+                listener,
+                admin,
+                header_read_timeout,
+                drain,
+                registration::shutdown(),
+            ));
+        }
+        None => info!("no admin_listen, /_lb/ endpoints are not served"),
     }
+    while servers.join_next().await.is_some() {}
+    info!("load balancer stopped");
+}
 
-    let app = Router::new()
-        .route("/{*wildcard}", get(handle_request).post(handle_request))
-        .with_state(load_balancer_state);
+async fn expire_registrations(balancer: Balancer) {
+    loop {
+        sleep(Duration::from_secs(1)).await;
+        balancer.expire(now_millis());
+    }
+}
 
-    let listener = TcpListener::bind(address)
+async fn bind(address: SocketAddr) -> TcpListener {
+    TcpListener::bind(address)
         .await
-        .expect("failed to listen...");
-    info!("loadbalancer is listening...");
-    axum::serve(listener, app).await.unwrap();
+        .unwrap_or_else(|e| panic!("failed to listen on {}: {}", address, e))
+}
+
+//reserved for the balancer itself, only served on the admin address so node
+//addresses and health never show on the traffic addresses
+fn admin_routes() -> Router<Balancer> {
+    Router::new().route("/_lb/stats", get(stats_handler)).route(
+        registration::PATH,
+        post(register_handler).delete(deregister_handler),
+    )
+}
+
+//every method is proxied, including OPTIONS so CORS preflights reach the nodes.
+//the admin paths are never proxied, so a node agent that was pointed at the
+//traffic port does not hand its token to a backend
+fn proxy_routes() -> Router<Balancer> {
+    Router::new()
+        .route("/_lb/{*rest}", any(|| async { StatusCode::NOT_FOUND }))
+        .route("/", any(handle_request))
+        .route("/{*wildcard}", any(handle_request))
 }
 
 async fn handle_request(
-    Path(_path): Path<String>,
-    lb: State<LoadBalancerState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    State(balancer): State<Balancer>,
     req: Request<Body>,
-) -> Result<Response<String>, StatusCode> {
+) -> Result<Response<Body>, StatusCode> {
+    let lb = balancer.current();
     let method = req.method().clone();
     let uri = req.uri().clone();
     let path = uri.path();
@@ -189,5 +1153,369 @@ async fn handle_request(
 
     info!("Incoming request: {} {}?{}", method, path, query);
 
-    lb.forward_request(req).await
+    let result = lb.forward_request(req, client).await;
+    if let Err(StatusCode::GATEWAY_TIMEOUT) = result {
+        if let Err(e) = update_error_code(path, &StatusCode::GATEWAY_TIMEOUT, &lb.db).await {
+            warn!("error code failed to update, moitering might not work as expected!");
+            error!("{}", e);
+        }
+    }
+    result
+}
+
+#[derive(Serialize)]
+struct NodeReport {
+    address: String,
+    health: String,
+    connections: usize,
+    pending: usize,
+    latency_ms: f64,
+    load: f64,
+    ejected: bool,
+    breaker: BreakerState,
+}
+
+//live view of every node for dashboards and debugging
+async fn stats_handler(State(balancer): State<Balancer>) -> Json<Vec<NodeReport>> {
+    let lb = balancer.current();
+    let now = now_millis();
+    let reports = lb
+        .servers
+        .iter()
+        .zip(lb.stats.iter())
+        .map(|(node, stats)| NodeReport {
+            address: node.address(),
+            health: stats.health.state().to_string(),
+            connections: stats.connections(),
+            pending: stats.breaker.pending(),
+            latency_ms: stats.latency_micros() / 1000.0,
+            load: stats.load(),
+            ejected: stats.outlier.is_ejected(now),
+            breaker: stats.breaker.state(now, &lb.circuit_breaker),
+        })
+        .collect();
+    Json(reports)
+}
+
+//404 while registration is off so the endpoint does not advertise itself
+fn check_registration(balancer: &Balancer, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let registration = balancer.registration();
+    if !registration.enabled {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if !registration::authorized(headers, registration.token.as_deref()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(())
+}
+
+async fn register_handler(
+    State(balancer): State<Balancer>,
+    headers: HeaderMap,
+    Json(registration): Json<Registration>,
+) -> Result<Json<Lease>, (StatusCode, String)> {
+    check_registration(&balancer, &headers).map_err(|status| (status, String::new()))?;
+    balancer.register(registration, now_millis()).map(Json)
+}
+
+async fn deregister_handler(
+    State(balancer): State<Balancer>,
+    headers: HeaderMap,
+    Json(deregistration): Json<Deregistration>,
+) -> StatusCode {
+    if let Err(status) = check_registration(&balancer, &headers) {
+        return status;
+    }
+    if balancer.deregister(&deregistration.address) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::health::Health;
+
+    fn config(nodes: &[&str]) -> LoadBalancerConfig {
+        LoadBalancerConfig {
+            ip: "127.0.0.1".to_string(),
+            nodes: nodes.iter().map(|node| node.parse().unwrap()).collect(),
+            ..LoadBalancerConfig::default()
+        }
+    }
+
+    fn state(nodes: &[&str]) -> LoadBalancerState {
+        let db = PgPool::connect_lazy("postgres://localhost/cluster").unwrap();
+        LoadBalancerState::new(db, config(nodes), None)
+    }
+
+    #[tokio::test]
+    async fn least_connections_skips_nodes_at_their_cap() {
+        let lb = state(&[
+            "10.0.0.1:8080",
+            "10.0.0.2:8080",
+            "10.0.0.3:8080,max_connections=1",
+        ]);
+        let _busy: Vec<ConnectionGuard> = [0, 0, 1]
+            .iter()
+            .map(|&i| ConnectionGuard::acquire(&lb.stats[i]))
+            .collect();
+        for _ in 0..3 {
+            assert_eq!(lb.least_connections(), Some(2));
+        }
+        //the idle node is full once it holds its one connection
+        let _full = ConnectionGuard::acquire(&lb.stats[2]);
+        for _ in 0..3 {
+            assert_eq!(lb.least_connections(), Some(1));
+        }
+    }
+
+    #[tokio::test]
+    async fn least_response_favours_the_fastest_node() {
+        let lb = state(&["10.0.0.1:8080", "10.0.0.2:8080", "10.0.0.3:8080"]);
+        lb.stats[0].record_latency(Duration::from_millis(100));
+        lb.stats[0].record_latency(Duration::from_millis(200));
+        assert_eq!(lb.stats[0].latency_micros(), 130_000.0);
+        lb.stats[1].record_latency(Duration::from_millis(20));
+        lb.stats[2].record_latency(Duration::from_millis(300));
+
+        let mut picks = [0; 3];
+        for _ in 0..3000 {
+            picks[lb.least_response().unwrap()] += 1;
+        }
+        //about one pick in ten goes to a random node, a third of those to the fastest
+        assert!(picks[1] > 2600, "{:?}", picks);
+        assert!(picks[0] > 0 && picks[2] > 0, "{:?}", picks);
+        assert!(picks[0] < 250 && picks[2] < 250, "{:?}", picks);
+    }
+
+    #[tokio::test]
+    async fn resource_aware_picks_the_least_loaded_node() {
+        let lb = state(&["10.0.0.1:8080", "10.0.0.2:8080", "10.0.0.3:8080"]);
+        lb.stats[0].record_load(80.0, 0.2);
+        lb.stats[1].record_load(20.0, 0.4);
+        lb.stats[2].record_unreachable();
+        assert_eq!(lb.stats[0].load(), 0.5);
+        assert!((lb.stats[1].load() - 0.3).abs() < 1e-9);
+        assert_eq!(lb.stats[2].load(), 1.0);
+        //cpu over 100 from a misreporting agent still counts as fully loaded
+        lb.stats[2].record_load(400.0, 1.0);
+        assert_eq!(lb.stats[2].load(), 1.0);
+        for _ in 0..3 {
+            assert_eq!(lb.resource_aware(), Some(1));
+        }
+    }
+
+    #[tokio::test]
+    async fn weighted_robin_round_interleaves_like_nginx() {
+        let lb = state(&["a:80,weight=5", "b:80,weight=1", "c:80,weight=1"]);
+        let picks: String = (0..14)
+            .map(|_| ["a", "b", "c"][lb.weighted_robin_round().unwrap()])
+            .collect();
+        assert_eq!(picks, "aabacaaaabacaa");
+    }
+
+    async fn upstream(app: Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        address.to_string()
+    }
+
+    fn client() -> SocketAddr {
+        "127.0.0.1:40000".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn retryable_answer_reaches_the_client_without_another_node() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&hits);
+        let address = upstream(Router::new().fallback(move || async move {
+            counted.fetch_add(1, Ordering::SeqCst);
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [("retry-after", "7")],
+                "busy",
+            )
+        }))
+        .await;
+        let lb = state(&[&address]);
+        let request = Request::get("/orders").body(Body::empty()).unwrap();
+        let response = lb.forward_request(request, client()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["retry-after"], "7");
+        let body = to_bytes(response.into_body(), 1024).await.unwrap();
+        assert_eq!(body, "busy");
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn admin_paths_are_not_proxied() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&hits);
+        let address = upstream(Router::new().fallback(move || async move {
+            counted.fetch_add(1, Ordering::SeqCst);
+            "proxied"
+        }))
+        .await;
+        let db = PgPool::connect_lazy("postgres://localhost/cluster").unwrap();
+        let app = proxy_routes().with_state(Balancer::new(db, config(&[&address])));
+        let send = |request: Request<Body>| {
+            let mut request = request;
+            request.extensions_mut().insert(ConnectInfo(client()));
+            tower::ServiceExt::oneshot(app.clone(), request)
+        };
+        let register = Request::post("/_lb/nodes")
+            .header("authorization", "Bearer secret")
+            .body(Body::from("{}"))
+            .unwrap();
+        let response = send(register).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let stats = Request::get("/_lb/stats").body(Body::empty()).unwrap();
+        assert_eq!(send(stats).await.unwrap().status(), StatusCode::NOT_FOUND);
+        assert_eq!(hits.load(Ordering::SeqCst), 0);
+
+        let orders = Request::get("/orders").body(Body::empty()).unwrap();
+        assert_eq!(send(orders).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn every_method_and_the_root_path_are_proxied() {
+        let address = upstream(Router::new().fallback(
+            |method: axum::http::Method, uri: axum::http::Uri, body: String| async move {
+                format!("{} {} {}", method, uri, body)
+            },
+        ))
+        .await;
+        let db = PgPool::connect_lazy("postgres://localhost/cluster").unwrap();
+        let app = proxy_routes().with_state(Balancer::new(db, config(&[&address])));
+        for (method, path, body) in [
+            ("GET", "/", ""),
+            ("POST", "/", "created"),
+            ("PUT", "/orders/1", "replaced"),
+            ("PATCH", "/orders/1?fields=state", "patched"),
+            ("DELETE", "/orders/1", ""),
+            ("OPTIONS", "/", ""),
+        ] {
+            let mut request = Request::builder()
+                .method(method)
+                .uri(path)
+                .header("origin", "https://shop.example")
+                .body(Body::from(body))
+                .unwrap();
+            request.extensions_mut().insert(ConnectInfo(client()));
+            let response = tower::ServiceExt::oneshot(app.clone(), request)
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{} {}", method, path);
+            let answer = to_bytes(response.into_body(), 1024).await.unwrap();
+            assert_eq!(answer, format!("{} {} {}", method, path, body));
+        }
+    }
+
+    #[tokio::test]
+    async fn bodies_of_unknown_size_are_streamed() {
+        let address =
+            upstream(Router::new().fallback(|body: Bytes| async move { body.len().to_string() }))
+                .await;
+        let lb = state(&[&address]);
+        //no content-length, like any http/2 body, and over max_replay_bytes
+        let chunks = (0..3).map(|_| Ok::<_, io::Error>(Bytes::from(vec![b'x'; 50_000])));
+        let request = Request::put("/orders/1")
+            .body(Body::from_stream(stream::iter(chunks)))
+            .unwrap();
+        let response = lb.forward_request(request, client()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), 1024).await.unwrap();
+        assert_eq!(body, "150000");
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_the_state_of_known_nodes() {
+        let db = PgPool::connect_lazy("postgres://localhost/cluster").unwrap();
+        let balancer = Balancer::new(db, config(&["10.0.0.1:8080", "10.0.0.2:8080"]));
+        let old = balancer.current();
+        let _connection = ConnectionGuard::acquire(&old.stats[1]);
+
+        balancer.reload(config(&["10.0.0.2:8080,draining", "10.0.0.3:8080"]));
+        let new = balancer.current();
+        assert!(Arc::ptr_eq(&old.stats[1], &new.stats[0]));
+        assert_eq!(new.stats[0].connections(), 1);
+        assert_eq!(new.stats[0].health.state(), Health::Draining);
+        assert_eq!(new.stats[1].connections(), 0);
+        assert_eq!(old.servers.len(), 2);
+        assert_eq!(old.sticky_secret, new.sticky_secret);
+
+        balancer.reload(config(&["10.0.0.2:8080"]));
+        assert!(balancer.current().stats[0].health.is_healthy());
+    }
+
+    fn registration(node: &str) -> Registration {
+        Registration {
+            name: "orders".to_string(),
+            node: node.parse().unwrap(),
+        }
+    }
+
+    fn registering(nodes: &[&str]) -> LoadBalancerConfig {
+        let mut cfg = config(nodes);
+        cfg.registration.enabled = true;
+        cfg.registration.token = Some("secret".to_string());
+        cfg.admin_listen = Some("127.0.0.1:3002".to_string());
+        cfg
+    }
+
+    #[tokio::test]
+    async fn registered_nodes_join_the_static_ones() {
+        let db = PgPool::connect_lazy("postgres://localhost/cluster").unwrap();
+        let cfg = registering(&["10.0.0.1:8080"]);
+        let balancer = Balancer::new(db, cfg.clone());
+
+        let lease = balancer.register(registration("10.0.0.5:8081"), 0).unwrap();
+        assert_eq!(lease.ttl_ms, cfg.registration.ttl_ms);
+        let registered = balancer.current();
+        assert_eq!(registered.servers.len(), 2);
+        //the same registration again keeps the generation
+        balancer.register(registration("10.0.0.5:8081"), 0).unwrap();
+        assert!(Arc::ptr_eq(&registered, &balancer.current()));
+
+        balancer
+            .register(registration("10.0.0.5:8081,draining"), 0)
+            .unwrap();
+        assert_eq!(balancer.current().stats[1].health.state(), Health::Draining);
+        let (status, _) = balancer
+            .register(registration("10.0.0.1:8080"), 0)
+            .unwrap_err();
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        //a reload keeps registered nodes
+        balancer.reload(cfg);
+        assert_eq!(balancer.current().servers.len(), 2);
+        assert!(balancer.deregister("10.0.0.5:8081"));
+        assert!(!balancer.deregister("10.0.0.1:8080"));
+        assert_eq!(balancer.current().servers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn heartbeats_keep_registrations_alive() {
+        let db = PgPool::connect_lazy("postgres://localhost/cluster").unwrap();
+        let mut cfg = registering(&[]);
+        cfg.registration.ttl_ms = 1000;
+        let balancer = Balancer::new(db, cfg);
+
+        balancer.register(registration("10.0.0.5:8081"), 0).unwrap();
+        balancer.register(registration("10.0.0.6:8081"), 0).unwrap();
+        balancer
+            .register(registration("10.0.0.5:8081"), 800)
+            .unwrap();
+        balancer.expire(999);
+        assert_eq!(balancer.current().servers.len(), 2);
+        balancer.expire(1000);
+        let servers = &balancer.current().servers;
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].address(), "10.0.0.5:8081");
+    }
 }
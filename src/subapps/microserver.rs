@@ -0,0 +1,257 @@
+//several small services on one host, each behind a local reverse proxy that
+//balancers send its traffic to. every service is registered with its balancers
+//as a node that drains while its health path fails, kept alive with heartbeats
+//and deregistered on shutdown. the embedded agent reports the host's metrics
+//like a node does
+use crate::common::forwarding::{request_headers, response_headers};
+use crate::common::registration::{deregister_all, keep_registered, shutdown, Registration};
+use crate::common::sampler::Sampler;
+use crate::config::loadbalancer_config::Node;
+use crate::config::microserver_config::{MicroServerConfig, ServiceConfig};
+use crate::config::server_config::ServerListener;
+use crate::subapps::node::agent_router;
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::{ConnectInfo, State},
+    http::{Request, Response, StatusCode},
+    response::IntoResponse,
+    Router,
+};
+use log::{error, info, warn};
+use reqwest::Client;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::sleep;
+
+struct Proxy {
+    upstream: String, //the service on loopback, like http://127.0.0.1:8080
+    client: Client,
+    trust_forwarded_headers: bool,
+}
+
+//the node balancers see for a service, `draining` follows its health
+fn service_node(cfg: &MicroServerConfig, service: &ServiceConfig) -> Node {
+    let address = cfg
+        .proxy_address(service)
+        .expect("the micro server config is validated before it runs");
+    let mut node: Node = format!("http://{}", address)
+        .parse()
+        .expect("a socket address is a valid node");
+    node.agent_port = cfg.agent_port;
+    node.weight = service.weight;
+    node
+}
+
+fn local_url(port: u16, path: &str) -> String {
+    format!(
+        "http://{}{}",
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+        path
+    )
+}
+
+async fn bind(address: SocketAddr) -> TcpListener {
+    let listener = TcpListener::bind(address)
+        .await
+        .unwrap_or_else(|e| panic!("failed to listen on {}: {}", address, e));
+    info!("micro server is listening on {}", address);
+    listener
+}
+
+pub async fn micro_server(cfg: MicroServerConfig) {
+    let ip: IpAddr = cfg
+        .ip
+        .parse()
+        .expect("the micro server config is validated before it runs");
+    let client = Client::new();
+    let interval = Duration::from_millis(cfg.health_interval_ms);
+
+    let mut servers: JoinSet<io::Result<()>> = JoinSet::new();
+    let agent = bind(SocketAddr::new(ip, cfg.agent_port)).await;
+    let sampler = Sampler::start(Duration::from_millis(cfg.metrics_interval_ms));
+    let agent_app = agent_router(&[ServerListener::HealthCheckListener], None, sampler);
+    servers.spawn(async move { axum::serve(agent, agent_app).await });
+
+    for service in &cfg.services {
+        let listener = bind(SocketAddr::new(ip, service.proxy_port)).await;
+        let proxy = Proxy {
+            upstream: local_url(service.port, ""),
+            client: client.clone(),
+            trust_forwarded_headers: cfg.trust_forwarded_headers,
+        };
+        let app = Router::new()
+            .fallback(forward)
+            .with_state(Arc::new(proxy))
+            .into_make_service_with_connect_info::<SocketAddr>();
+        servers.spawn(async move { axum::serve(listener, app).await });
+
+        //balancers only hear of the service once its first health check is in
+        let health_url = local_url(service.port, &service.health_path);
+        let mut node = service_node(&cfg, service);
+        node.draining = !healthy(&client, &health_url, interval).await;
+        let (registration, receiver) = watch::channel(Registration {
+            name: service.name.clone(),
+            node,
+        });
+        for balancer in &service.loadbalancers {
+            tokio::spawn(keep_registered(
+                client.clone(),
+                balancer.clone(),
+                cfg.token.clone(),
+                receiver.clone(),
+            ));
+        }
+        tokio::spawn(watch_health(
+            client.clone(),
+            health_url,
+            registration,
+            interval,
+        ));
+    }
+
+    tokio::select! {
+        _ = async {
+            while let Some(served) = servers.join_next().await {
+                if let Ok(Err(e)) = served {
+                    error!("{}", e);
+                }
+            }
+        } => {}
+        _ = shutdown() => {
+            info!("shutting down, deregistering every service");
+            for service in &cfg.services {
+                let address = service_node(&cfg, service).address();
+                deregister_all(&client, &service.loadbalancers, cfg.token.as_deref(), &address)
+                    .await;
+            }
+        }
+    }
+}
+
+//streams the request to the service and its response back
+async fn forward(
+    State(proxy): State<Arc<Proxy>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    req: Request<Body>,
+) -> Response<Body> {
+    let (parts, body) = req.into_parts();
+    let path_and_query = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+    let uri = format!("{}{}", proxy.upstream, path_and_query);
+    //anyone who reaches the proxy port could claim another address otherwise
+    let headers = request_headers(&parts.headers, client, proxy.trust_forwarded_headers);
+    let mut request = proxy.client.request(parts.method, &uri).headers(headers);
+    //a streamed body keeps the client's content-length, a missing body stays missing
+    if !body.is_end_stream() {
+        request = request.body(reqwest::Body::wrap_stream(body.into_data_stream()));
+    }
+
+    match request.send().await {
+        Ok(response) => {
+            let mut builder = Response::builder().status(response.status());
+            if let Some(builder_headers) = builder.headers_mut() {
+                *builder_headers = response_headers(response.headers());
+            }
+            builder
+                .body(Body::from_stream(response.bytes_stream()))
+                .unwrap()
+        }
+        Err(e) => {
+            warn!("failed to reach {}: {}", uri, e);
+            StatusCode::BAD_GATEWAY.into_response()
+        }
+    }
+}
+
+async fn healthy(client: &Client, url: &str, timeout: Duration) -> bool {
+    match client.get(url).timeout(timeout).send().await {
+        Ok(response) => response.status().is_success(),
+        Err(_) => false,
+    }
+}
+
+//drains the service on every balancer while its health path fails
+async fn watch_health(
+    client: Client,
+    health_url: String,
+    registration: watch::Sender<Registration>,
+    interval: Duration,
+) {
+    loop {
+        sleep(interval).await;
+        let draining = !healthy(&client, &health_url, interval).await;
+        registration.send_if_modified(|registration| {
+            if registration.node.draining == draining {
+                return false;
+            }
+            let state = if draining { "unhealthy" } else { "healthy" };
+            warn!("service {} is {}", registration.name, state);
+            registration.node.draining = draining;
+            true
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn services_register_their_proxy() {
+        let service = ServiceConfig {
+            name: "orders".to_string(),
+            port: 8080,
+            proxy_port: 8081,
+            health_path: "/health".to_string(),
+            weight: 3,
+            loadbalancers: vec!["10.0.0.1:3002".to_string()],
+        };
+        let cfg = MicroServerConfig {
+            ip: "fd00::5".to_string(),
+            agent_port: 3005,
+            services: vec![service.clone()],
+            ..MicroServerConfig::default()
+        };
+        let node = service_node(&cfg, &service);
+        assert_eq!(node.address(), "[fd00::5]:8081");
+        assert_eq!(node.agent_port, 3005);
+        assert_eq!(node.weight, 3);
+        assert!(!node.draining);
+        assert_eq!(local_url(8080, "/health"), "http://127.0.0.1:8080/health");
+    }
+
+    #[tokio::test]
+    async fn forwarded_headers_are_only_kept_when_trusted() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = format!("http://{}", listener.local_addr().unwrap());
+        let echo = Router::new().fallback(|headers: axum::http::HeaderMap| async move {
+            headers["x-forwarded-for"].to_str().unwrap().to_string()
+        });
+        tokio::spawn(async move { axum::serve(listener, echo).await });
+
+        for (trust, expected) in [(false, "10.0.0.1"), (true, "203.0.113.9, 10.0.0.1")] {
+            let proxy = Proxy {
+                upstream: upstream.clone(),
+                client: Client::new(),
+                trust_forwarded_headers: trust,
+            };
+            let app = Router::new().fallback(forward).with_state(Arc::new(proxy));
+            let mut request = Request::get("/orders")
+                .header("x-forwarded-for", "203.0.113.9")
+                .body(Body::empty())
+                .unwrap();
+            let peer: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+            request.extensions_mut().insert(ConnectInfo(peer));
+            let response = tower::ServiceExt::oneshot(app, request).await.unwrap();
+            let body = axum::body::to_bytes(response.into_body(), 1024)
+                .await
+                .unwrap();
+            assert_eq!(body, expected);
+        }
+    }
+}
@@ -1,2 +1,3 @@
 pub mod loadbalancer;
-pub mod node;
\ No newline at end of file
+pub mod microserver;
+pub mod node;
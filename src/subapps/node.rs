@@ -1,38 +1,158 @@
 //server listens to loadbalancer for giving the no of connections, metrics
 //this works in parallel to the application run by the user
-use crate::config::server_config::ServerConfig;
-use axum::{http::StatusCode, response::IntoResponse, routing::get, Json, Router};
-use log::{error, warn};
+use crate::common::api_checks::{self, check_apis, ApiReport};
+use crate::common::network::NetworkRates;
+use crate::common::registration::{deregister_all, keep_registered, shutdown, Registration};
+use crate::common::sampler::{Sample, Sampler, Trend};
+use crate::config::server_config::{ServerConfig, ServerListener};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use log::{error, info, warn};
 use netstat2::{get_sockets_info, AddressFamilyFlags, ProtocolFlags, ProtocolSocketInfo};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use sysinfo::{CpuRefreshKind, RefreshKind, System};
-use tokio::time::Instant;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use sysinfo::System;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Metrics {
     pub cpu: f32,
     pub ram: f64,
-    pub netspeed: Vec<f64>,
+    pub netspeed: Vec<f64>, //megabits per second received and sent
+    pub network: NetworkRates,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history: Option<Trend>, //1m and 5m averages, when asked for with ?history=true
+}
+
+impl Metrics {
+    fn new(sample: Sample, history: Option<Trend>) -> Self {
+        Metrics {
+            cpu: sample.cpu,
+            ram: sample.ram,
+            netspeed: sample.network.mbps().to_vec(),
+            network: sample.network,
+            history,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct MetricsQuery {
+    #[serde(default)]
+    history: bool,
 }
 
 //listens to lb and sends the response
-pub async fn server_listener() {
-    let cfg: ServerConfig = confy::load("server-config", None).expect("Failed to load config");
-    let server_ip = cfg.ip;
-    let _listener = cfg.listener;
-    let _lbs = cfg.loadbalancer_ip;
-    let app = Router::new()
-        .route("/connections", get(connections_handler))
-        .route("/metrics", get(metrics_handler));
+pub async fn server_listener(cfg: ServerConfig) {
+    let addresses = cfg.listen_addresses();
+    let report = match &cfg.api_checks {
+        Some(api_checks)
+            if cfg
+                .listener
+                .contains(&ServerListener::ApiHealthCheckListener) =>
+        {
+            let report = Arc::new(RwLock::new(ApiReport::default()));
+            tokio::spawn(check_apis(api_checks.clone(), Arc::clone(&report)));
+            Some(report)
+        }
+        _ => None,
+    };
+    let sampler = Sampler::start(Duration::from_millis(cfg.metrics_interval_ms));
+    let app = agent_router(&cfg.listener, report, sampler);
+    if cfg.listener.is_empty() {
+        warn!("no listeners are selected, the agent serves nothing");
+    }
+
+    let mut servers = JoinSet::new();
+    for address in addresses {
+        let listener = TcpListener::bind(address)
+            .await
+            .unwrap_or_else(|e| panic!("failed to listen on {}: {}", address, e));
+        info!("server is listening on {}", address);
+        let app = app.clone();
+        servers.spawn(async move { axum::serve(listener, app).await });
+    }
 
-    let address = server_ip + ":3001";
-    dbg!(address.clone());
-    let listener = tokio::net::TcpListener::bind(address)
-        .await
-        .expect("failed to listen...");
-    println!("server is listening.....");
-    axum::serve(listener, app).await.unwrap();
+    let client = Client::new();
+    let balancers: Vec<String> = cfg
+        .balancer_addresses()
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    let token = cfg.registration.as_ref().and_then(|r| r.token.clone());
+    let node = cfg.node();
+    //the heartbeats stop once the sender is dropped on the way out
+    let _registration = node.as_ref().map(|node| {
+        let name = System::host_name().unwrap_or_else(|| cfg.ip.clone());
+        let (sender, receiver) = watch::channel(Registration {
+            name,
+            node: node.clone(),
+        });
+        for balancer in &balancers {
+            tokio::spawn(keep_registered(
+                client.clone(),
+                balancer.clone(),
+                token.clone(),
+                receiver.clone(),
+            ));
+        }
+        sender
+    });
+
+    tokio::select! {
+        _ = async {
+            while let Some(served) = servers.join_next().await {
+                if let Ok(Err(e)) = served {
+                    error!("{}", e);
+                }
+            }
+        } => {}
+        _ = shutdown() => {
+            info!("shutting down");
+            if let Some(node) = &node {
+                deregister_all(&client, &balancers, token.as_deref(), &node.address()).await;
+            }
+        }
+    }
+}
+
+//only what the selected listeners serve, `report` backs /apis and `sampler`
+//backs /metrics
+pub fn agent_router(
+    listeners: &[ServerListener],
+    report: Option<Arc<RwLock<ApiReport>>>,
+    sampler: Arc<Sampler>,
+) -> Router {
+    let mut router = Router::new();
+    if listeners.contains(&ServerListener::HealthCheckListener) {
+        router = router
+            .route("/connections", get(connections_handler))
+            .route("/metrics", get(metrics_handler).with_state(sampler));
+    }
+    if let Some(report) = report {
+        router = router.route(api_checks::PATH, get(apis_handler).with_state(report));
+    }
+    router
+}
+
+//503 until every api passed its last check, the body says which failed
+async fn apis_handler(State(report): State<Arc<RwLock<ApiReport>>>) -> impl IntoResponse {
+    let report = report.read().unwrap().clone();
+    let status = if report.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
 }
 
 async fn connections_handler() -> impl IntoResponse {
@@ -40,15 +160,24 @@ async fn connections_handler() -> impl IntoResponse {
     (StatusCode::OK, count)
 }
 
-async fn metrics_handler() -> impl IntoResponse {
-    let metrics = get_metrics().await;
-    (StatusCode::OK, Json(metrics))
+//the latest sample, 503 until the sampler took its first one
+async fn metrics_handler(
+    State(sampler): State<Arc<Sampler>>,
+    Query(query): Query<MetricsQuery>,
+) -> impl IntoResponse {
+    match sampler.latest() {
+        Some(sample) => {
+            let history = if query.history { sampler.trend() } else { None };
+            Json(Metrics::new(sample, history)).into_response()
+        }
+        None => (StatusCode::SERVICE_UNAVAILABLE, "no metrics sampled yet").into_response(),
+    }
 }
 
 //gets the no connections with loadbalancers(only src of connection)
 //reading from /proc/net/tcp
 fn get_connections() -> String {
-    let af_flags = AddressFamilyFlags::IPV4;
+    let af_flags = AddressFamilyFlags::IPV4 | AddressFamilyFlags::IPV6;
     let proto_flags = ProtocolFlags::TCP;
 
     match get_sockets_info(af_flags, proto_flags) {
@@ -62,94 +191,3 @@ fn get_connections() -> String {
         Err(err) => format!("Failed to get connections: {}", err),
     }
 }
-
-//cpu consumptions
-//ram consumption
-//network speed
-async fn netspeed_download() -> f64 {
-    let url = " http://speedtest.tele2.net/1MB.zip";
-    let client = Client::new();
-
-    let start = Instant::now();
-    let response = client.get(url).send().await;
-    match response {
-        Ok(response) => {
-            let bytes = response
-                .bytes()
-                .await
-                .expect("should get the bytes recieved");
-            let elapsed = start.elapsed().as_secs_f64();
-
-            let size_in_mb = bytes.len() as f64 / (1024.0 * 1024.0);
-            let speed_mbps = size_in_mb / elapsed * 8.0;
-
-            speed_mbps
-        }
-        Err(e) => {
-            warn!("response from speedtest server failed!");
-            error!("{}", e);
-            return 0.0;
-        }
-    }
-}
-
-async fn _netspeed_upload() -> f64 {
-    let client = Client::new();
-    let url = "https://httpbin.org/post"; // accepts raw data
-
-    let data_size_bytes = 1 * 1024 * 1024; // 1 MB
-    let data = vec![0u8; data_size_bytes];
-
-    let start = Instant::now();
-    let response = client
-        .post(url)
-        .header("Content-Type", "application/octet-stream")
-        .body(data)
-        .send()
-        .await
-        .expect("Failed to send request");
-
-    println!("Response: {}", response.status());
-
-    let mut speed_mbps = 0.0;
-    if response.status().is_success() {
-        let elapsed = start.elapsed().as_secs_f64();
-        speed_mbps = (data_size_bytes as f64 * 8.0) / (elapsed * 1024.0 * 1024.0);
-        println!("Upload speed: {:.2} Mbps", speed_mbps);
-    } else {
-        println!("Server rejected request");
-    }
-
-    speed_mbps
-}
-
-async fn get_metrics() -> Metrics {
-    let mut sys = System::new_all();
-    sys.refresh_all();
-
-    let mut s =
-        System::new_with_specifics(RefreshKind::nothing().with_cpu(CpuRefreshKind::everything()));
-    std::thread::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL);
-    s.refresh_cpu_all();
-    let mut consumption = 0.0;
-    for cpu in s.cpus() {
-        consumption += cpu.cpu_usage();
-    }
-    let total_memory = sys.total_memory() / 1024;
-    let used_memory = sys.used_memory() / 1024;
-
-    let download = netspeed_download().await;
-    let upload = 0.0;
-
-    // println!(
-    //     "cpu: {:.2}% | memory: {}MB/{}MB | download: {:#?}mbps | upload: {:#?}mbps",
-    //     consumption, used_memory, total_memory, download, upload
-    // );
-
-    let ram: f64 = (used_memory) as f64 / (total_memory) as f64;
-    Metrics {
-        cpu: consumption,
-        ram,
-        netspeed: vec![download, upload],
-    }
-}
@@ -2,16 +2,15 @@ use indicatif::{ProgressBar, ProgressStyle};
 use std::process::Command;
 use std::{thread, time::Duration};
 
-use serde_json::Error as SerdeError;
-use std::fs;
-use std::io::Error as IoError;
-
 use crate::config::loadbalancer_config::LoadBalancerConfig;
 use crate::config::server_config::ServerConfig;
-use crate::subapps::loadbalancer::ApiConfig;
 
 pub fn validate_lb_config(config: &LoadBalancerConfig) -> bool {
-    let ips: Vec<String> = config.nodes.iter().map(|ip| ip.clone()).collect();
+    let ips: Vec<String> = config
+        .nodes
+        .iter()
+        .map(|node| node.host().trim_matches(['[', ']']).to_string())
+        .collect();
     let mut is_valid = true;
 
     let bar = ProgressBar::new(ips.len() as u64);
@@ -62,14 +61,18 @@ pub fn is_ip_live(ip: &str) -> bool {
 pub fn validate_server_config(config: &ServerConfig) -> bool {
     let ip = &config.ip;
     let mut valid = true;
-    if is_ip_live(&ip) {
+    if is_ip_live(ip) {
         println!("server {} is live", ip);
     } else {
         println!("server {} is not reachable", ip);
         let _ = !valid;
     }
 
-    let loadblancers: Vec<String> = config.loadbalancer_ip.iter().map(|ip| ip.clone()).collect();
+    let loadblancers: Vec<String> = config
+        .balancer_addresses()
+        .iter()
+        .map(|address| address.ip().to_string())
+        .collect();
     let bar = ProgressBar::new(loadblancers.len() as u64);
     bar.set_style(
         ProgressStyle::default_bar()
@@ -90,11 +93,3 @@ pub fn validate_server_config(config: &ServerConfig) -> bool {
     bar.finish_with_message("Validation complete.");
     valid
 }
-
-pub fn read_json_from_file(file_path: &str) -> Result<String, IoError> {
-    fs::read_to_string(file_path)
-}
-
-pub fn validate_person_json(json_str: &str) -> Result<ApiConfig, SerdeError> {
-    serde_json::from_str::<ApiConfig>(json_str)
-}